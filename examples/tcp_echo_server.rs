@@ -46,11 +46,12 @@ impl Machine for Echo {
     type Seed = TcpStream;
 
     fn create(conn: TcpStream, scope: &mut Scope<Context>)
-        -> Response<Self, Void>
+        -> Response<Self, TcpStream>
     {
-        scope.register(&conn, EventSet::readable(), PollOpt::level())
-            .unwrap();
-        Response::ok(Echo::Connection(conn))
+        match scope.register(&conn, EventSet::readable(), PollOpt::level()) {
+            Ok(()) => Response::ok(Echo::Connection(conn)),
+            Err(e) => Response::error(Box::new(e)),
+        }
     }
 
     fn ready(self, _events: EventSet, _scope: &mut Scope<Context>)
@@ -0,0 +1,421 @@
+//! Custom derive for composing rotor state machines
+//!
+//! This is an alternative to the `rotor_compose!` macro which works with
+//! generic child machines and allows to override handling of any single
+//! action for any single variant.
+//!
+//! # Example
+//!
+//! ```ignore
+//! #[macro_use] extern crate rotor_derive;
+//!
+//! #[derive(Machine)]
+//! #[rotor(seed="Seed")]
+//! enum Fsm<C> {
+//!     Http(HttpMachine<C>),
+//!     #[rotor(wakeup="dns_wakeup")]
+//!     Dns(DnsMachine<C>),
+//! }
+//!
+//! fn dns_wakeup<C>(m: DnsMachine<C>, scope: &mut Scope<C>)
+//!     -> Response<Fsm<C>, Seed<C>>
+//! {
+//!     // ...
+//! }
+//! ```
+//!
+//! The derive generates a `Seed` enum (named `<Name>Seed` unless the
+//! `seed` attribute is specified) with the same variants as the original
+//! enum, each wrapping the `Seed` of the respective machine. The seed
+//! implements `Debug` when seeds of all the children do. It also
+//! generates an implementation of `rotor::Machine` which dispatches every
+//! action to the wrapped machine.
+//!
+//! The following attributes are supported on the enum itself:
+//!
+//! * `#[rotor(seed="Name")]` -- name of the generated seed type
+//! * `#[rotor(context="Type")]` -- the context type. By default the context
+//!   of the first variant is used, all other variants must have the same one
+//!
+//! The following attributes may be specified on a variant, each replaces
+//! forwarding of a single action to the wrapped machine by a call of the
+//! specified function:
+//!
+//! * `#[rotor(create="func")]` -- `func(seed, scope)`
+//! * `#[rotor(ready="func")]` -- `func(machine, events, scope)`, it's
+//!   also called instead of `ready_source`, unless that one is overridden
+//! * `#[rotor(ready_source="func")]` -- `func(machine, events, source, scope)`
+//! * `#[rotor(spawned="func")]` -- `func(machine, scope)`
+//! * `#[rotor(spawn_error="func")]` -- `func(machine, scope, error)`, where
+//!   `error` is already converted into `SpawnError<Child::Seed>`
+//! * `#[rotor(child_exited="func")]` -- `func(machine, scope, child, reason)`
+//! * `#[rotor(timeout="func")]` -- `func(machine, scope)`
+//! * `#[rotor(wakeup="func")]` -- `func(machine, scope)`
+//!
+//! The functions return a response of the composed machine (not the child
+//! one), so they are free to switch to another variant.
+
+#![crate_name="rotor_derive"]
+#![recursion_limit="128"]
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use] extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as Tokens};
+use syn::{DeriveInput, Data, Fields, Ident, Type, Path, Lit, Meta};
+use syn::{NestedMeta, Attribute, Error};
+
+
+const ACTIONS: &'static [&'static str] = &[
+    "create", "ready", "ready_source", "spawned", "spawn_error",
+    "child_exited",
+    "timeout", "wakeup"];
+
+struct Variant {
+    name: Ident,
+    typ: Type,
+    overrides: Vec<(String, Path)>,
+}
+
+impl Variant {
+    fn handler(&self, action: &str) -> Option<&Path> {
+        self.overrides.iter()
+            .find(|&&(ref name, _)| name == action)
+            .map(|&(_, ref path)| path)
+    }
+}
+
+#[proc_macro_derive(Machine, attributes(rotor))]
+pub fn derive_machine(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match machine(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn attributes(attrs: &[Attribute]) -> Result<Vec<(String, Path)>, Error> {
+    let mut result = Vec::new();
+    for attr in attrs {
+        if !attr.path.is_ident("rotor") {
+            continue;
+        }
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            other => {
+                return Err(Error::new_spanned(other,
+                    "expected #[rotor(name=\"value\")]"));
+            }
+        };
+        for item in list.nested {
+            let pair = match item {
+                NestedMeta::Meta(Meta::NameValue(pair)) => pair,
+                other => {
+                    return Err(Error::new_spanned(other,
+                        "expected name=\"value\""));
+                }
+            };
+            let name = match pair.path.get_ident() {
+                Some(ident) => ident.to_string(),
+                None => {
+                    return Err(Error::new_spanned(pair.path,
+                        "expected an identifier"));
+                }
+            };
+            let value = match pair.lit {
+                Lit::Str(ref s) => s.parse::<Path>()?,
+                ref other => {
+                    return Err(Error::new_spanned(other,
+                        "expected a string literal"));
+                }
+            };
+            result.push((name, value));
+        }
+    }
+    Ok(result)
+}
+
+fn variants(input: &DeriveInput) -> Result<Vec<Variant>, Error> {
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => {
+            return Err(Error::new_spanned(&input.ident,
+                "#[derive(Machine)] only works on enums"));
+        }
+    };
+    let mut result = Vec::new();
+    for var in &data.variants {
+        let typ = match var.fields {
+            Fields::Unnamed(ref f) if f.unnamed.len() == 1 => {
+                f.unnamed[0].ty.clone()
+            }
+            _ => {
+                return Err(Error::new_spanned(&var.ident,
+                    "every variant must wrap exactly one state machine"));
+            }
+        };
+        let overrides = attributes(&var.attrs)?;
+        for &(ref name, ref path) in &overrides {
+            if !ACTIONS.contains(&&name[..]) {
+                return Err(Error::new_spanned(path,
+                    format!("unknown action {:?}, expected one of {:?}",
+                        name, ACTIONS)));
+            }
+        }
+        result.push(Variant {
+            name: var.ident.clone(),
+            typ: typ,
+            overrides: overrides,
+        });
+    }
+    if result.is_empty() {
+        return Err(Error::new_spanned(&input.ident,
+            "at least one variant is required"));
+    }
+    Ok(result)
+}
+
+fn machine(input: &DeriveInput) -> Result<Tokens, Error> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let variants = variants(input)?;
+    let mut seed = Ident::new(&format!("{}Seed", name), Span::call_site());
+    let mut context = None;
+    for (key, value) in attributes(&input.attrs)? {
+        match &key[..] {
+            "seed" => match value.get_ident() {
+                Some(ident) => seed = ident.clone(),
+                None => {
+                    return Err(Error::new_spanned(value,
+                        "seed must be a plain identifier"));
+                }
+            },
+            "context" => context = Some(value),
+            _ => {
+                return Err(Error::new_spanned(value,
+                    format!("unknown attribute {:?}, \
+                             expected `seed` or `context`", key)));
+            }
+        }
+    }
+
+    let first = &variants[0].typ;
+    let ctx = match context {
+        Some(ref path) => quote! { #path },
+        None => quote! { <#first as ::rotor::Machine>::Context },
+    };
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+    // Note: we don't put `Child: Machine` bounds into the where clause, as
+    // they would shadow the implementation and so associated types of the
+    // children would not be normalized. If implementation of a child is
+    // conditional, the bounds should be put on the enum itself.
+    let bounds = where_clause.map(|w| {
+        let preds = w.predicates.iter();
+        quote! { #( #preds, )* }
+    }).unwrap_or_else(Tokens::new);
+
+    let seed_variants = variants.iter().map(|var| {
+        let vname = &var.name;
+        let typ = &var.typ;
+        quote! { #vname(<#typ as ::rotor::Machine>::Seed) }
+    });
+    let generics = &input.generics.params;
+    let seed_debug = variants.iter().map(|var| {
+        let vname = &var.name;
+        quote! {
+            #seed::#vname(ref s) => f.debug_tuple(stringify!(#vname))
+                .field(s).finish(),
+        }
+    });
+    let debug_bounds = variants.iter().map(|var| {
+        let typ = &var.typ;
+        quote! { <#typ as ::rotor::Machine>::Seed: ::std::fmt::Debug }
+    });
+
+    let create = variants.iter().map(|var| {
+        let vname = &var.name;
+        match var.handler("create") {
+            Some(fun) => quote! {
+                #seed::#vname(s) => #fun(s, scope),
+            },
+            None => quote! {
+                #seed::#vname(s) => ::rotor::Machine::create(s, scope)
+                    .map(#name::#vname, #seed::#vname),
+            },
+        }
+    });
+    let ready = dispatch(name, &seed, &variants, "ready",
+        quote! { events, scope });
+    let ready_source = variants.iter().map(|var| {
+        let vname = &var.name;
+        match (var.handler("ready_source"), var.handler("ready")) {
+            (Some(fun), _) => quote! {
+                #name::#vname(m) => #fun(m, events, source, scope),
+            },
+            (None, Some(fun)) => quote! {
+                #name::#vname(m) => #fun(m, events, scope),
+            },
+            (None, None) => quote! {
+                #name::#vname(m) => m.ready_source(events, source, scope)
+                    .map(#name::#vname, #seed::#vname),
+            },
+        }
+    });
+    let spawned = dispatch(name, &seed, &variants, "spawned",
+        quote! { scope });
+    let child_exited = dispatch(name, &seed, &variants, "child_exited",
+        quote! { scope, child, reason });
+    let timeout = dispatch(name, &seed, &variants, "timeout",
+        quote! { scope });
+    let wakeup = dispatch(name, &seed, &variants, "wakeup",
+        quote! { scope });
+    let describe = variants.iter().map(|var| {
+        let vname = &var.name;
+        quote! { #name::#vname(ref m) => ::rotor::Machine::describe(m), }
+    });
+    let machine_name = variants.iter().map(|var| {
+        let vname = &var.name;
+        quote! { #name::#vname(ref m) => ::rotor::Machine::name(m), }
+    });
+    let spawn_error = variants.iter().map(|var| {
+        let vname = &var.name;
+        let error = quote! {
+            error.map(|s| match s {
+                #seed::#vname(s) => s,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("Seed returned from {}::{} \
+                    is of different type", stringify!(#name),
+                    stringify!(#vname)),
+            })
+        };
+        match var.handler("spawn_error") {
+            Some(fun) => quote! {
+                #name::#vname(m) => #fun(m, scope, #error),
+            },
+            None => quote! {
+                #name::#vname(m) => m.spawn_error(scope, #error)
+                    .map(#name::#vname, #seed::#vname),
+            },
+        }
+    });
+
+    Ok(quote! {
+        #vis enum #seed<#generics> where #bounds {
+            #( #seed_variants, )*
+        }
+
+        impl #impl_generics ::std::fmt::Debug for #seed #ty_generics
+            where #bounds #( #debug_bounds, )*
+        {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter)
+                -> ::std::fmt::Result
+            {
+                match *self {
+                    #( #seed_debug )*
+                }
+            }
+        }
+
+        impl #impl_generics ::rotor::Machine for #name #ty_generics
+            where #bounds
+        {
+            type Context = #ctx;
+            type Seed = #seed #ty_generics;
+            fn create(seed: Self::Seed,
+                      scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match seed {
+                    #( #create )*
+                }
+            }
+            fn ready(self, events: ::rotor::EventSet,
+                     scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #ready )*
+                }
+            }
+            fn ready_source(self, events: ::rotor::EventSet,
+                            source: ::rotor::Source,
+                            scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #ready_source )*
+                }
+            }
+            fn spawned(self, scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #spawned )*
+                }
+            }
+            fn spawn_error(self, scope: &mut ::rotor::Scope<Self::Context>,
+                           error: ::rotor::SpawnError<Self::Seed>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #spawn_error )*
+                }
+            }
+            fn child_exited(self, scope: &mut ::rotor::Scope<Self::Context>,
+                            child: ::rotor::mio::Token, reason: ::rotor::Exit)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #child_exited )*
+                }
+            }
+            fn timeout(self, scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #timeout )*
+                }
+            }
+            fn describe(&self) -> Option<String> {
+                match *self {
+                    #( #describe )*
+                }
+            }
+            fn name(&self) -> &'static str {
+                match *self {
+                    #( #machine_name )*
+                }
+            }
+            fn wakeup(self, scope: &mut ::rotor::Scope<Self::Context>)
+                -> ::rotor::Response<Self, Self::Seed>
+            {
+                match self {
+                    #( #wakeup )*
+                }
+            }
+        }
+    })
+}
+
+fn dispatch(name: &Ident, seed: &Ident, variants: &[Variant],
+    action: &str, args: Tokens)
+    -> Vec<Tokens>
+{
+    let method = Ident::new(action, Span::call_site());
+    variants.iter().map(|var| {
+        let vname = &var.name;
+        match var.handler(action) {
+            Some(fun) => quote! {
+                #name::#vname(m) => #fun(m, #args),
+            },
+            None => quote! {
+                #name::#vname(m) => m.#method(#args)
+                    .map(#name::#vname, #seed::#vname),
+            },
+        }
+    }).collect()
+}
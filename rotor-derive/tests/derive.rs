@@ -0,0 +1,166 @@
+extern crate rotor;
+#[macro_use] extern crate rotor_derive;
+
+use std::io;
+use std::marker::PhantomData;
+
+use rotor::{Machine, Response, Scope, EventSet, PollOpt, Evented};
+use rotor::{SpawnError, Time, Timeout, TimerError, _LoopApi, _Notify, _Timeo};
+use rotor::mio::Token;
+use rotor::mio::deprecated::{EventLoop, Handler};
+
+
+#[derive(Debug)]
+struct Context {
+    log: Vec<&'static str>,
+}
+
+struct Idle;
+
+impl Handler for Idle {
+    type Timeout = _Timeo;
+    type Message = _Notify;
+}
+
+struct NoLoop;
+
+impl _LoopApi for NoLoop {
+    fn register(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()> { Ok(()) }
+    fn reregister(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()> { Ok(()) }
+    fn deregister(&mut self, _io: &Evented) -> io::Result<()> { Ok(()) }
+    fn timeout_ms(&mut self, _token: Token, _delay: u64)
+        -> Result<Timeout, TimerError> { Err(TimerError) }
+    fn clear_timeout(&mut self, _token: Timeout) -> bool { false }
+    fn shutdown(&mut self) {}
+}
+
+fn with_scope<F: FnOnce(&mut Scope<Context>)>(ctx: &mut Context, f: F) {
+    let eloop = EventLoop::<Idle>::new().unwrap();
+    let mut channel = eloop.channel();
+    let mut lapi = NoLoop;
+    f(&mut rotor::_scope(Time::zero(), Token(0), ctx, &mut channel,
+                         &mut lapi));
+}
+
+/// A generic child, which `rotor_compose!` can't work with
+#[derive(Debug)]
+struct Child<C>(u32, PhantomData<C>);
+
+impl<C> Machine for Child<C> {
+    type Context = C;
+    type Seed = u32;
+    fn create(seed: u32, _scope: &mut Scope<C>) -> Response<Self, u32> {
+        Response::ok(Child(seed, PhantomData))
+    }
+    fn ready(self, _events: EventSet, _scope: &mut Scope<C>)
+        -> Response<Self, u32>
+    {
+        let x = self.0;
+        Response::spawn(self, x + 1)
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, u32> {
+        Response::ok(self)
+    }
+    fn spawn_error(self, _scope: &mut Scope<C>, error: SpawnError<u32>)
+        -> Response<Self, u32>
+    {
+        match error {
+            SpawnError::NoSlabSpace(x) => Response::ok(Child(x, PhantomData)),
+            SpawnError::UserError(e) => Response::error(e),
+        }
+    }
+    fn timeout(self, _scope: &mut Scope<C>) -> Response<Self, u32> {
+        Response::done()
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, u32> {
+        Response::ok(self)
+    }
+}
+
+#[derive(Machine, Debug)]
+enum Fsm<C> {
+    First(Child<C>),
+    #[rotor(wakeup="second_wakeup")]
+    Second(Child<C>),
+}
+
+fn second_wakeup<C>(m: Child<C>, scope: &mut Scope<C>)
+    -> Response<Fsm<C>, FsmSeed<C>>
+{
+    let _ = scope;
+    Response::ok(Fsm::First(Child(m.0 * 10, PhantomData)))
+}
+
+#[derive(Machine, Debug)]
+#[rotor(seed="Seed", context="Context")]
+enum Concrete {
+    #[rotor(timeout="log_timeout")]
+    Main(Child<Context>),
+}
+
+fn log_timeout(m: Child<Context>, scope: &mut Scope<Context>)
+    -> Response<Concrete, Seed>
+{
+    scope.log.push("timeout");
+    Response::ok(Concrete::Main(m))
+}
+
+#[test]
+fn forward_actions() {
+    let mut ctx = Context { log: Vec::new() };
+    with_scope(&mut ctx, |scope| {
+        let m = Fsm::create(FsmSeed::Second(7), scope).expect_machine();
+        let (m, seed) = m.ready(EventSet::readable(), scope).expect_spawn();
+        match seed {
+            FsmSeed::Second(8) => {}
+            _ => panic!("wrong seed"),
+        }
+        match m {
+            Fsm::Second(Child(7, _)) => {}
+            _ => panic!("wrong machine"),
+        }
+        m.timeout(scope).expect_done();
+    });
+}
+
+#[test]
+fn override_action() {
+    let mut ctx = Context { log: Vec::new() };
+    with_scope(&mut ctx, |scope| {
+        let m = Fsm::Second(Child(3, PhantomData));
+        match m.wakeup(scope).expect_machine() {
+            Fsm::First(Child(30, _)) => {}
+            _ => panic!("wakeup is not overriden"),
+        }
+        let m = Concrete::create(Seed::Main(1), scope).expect_machine();
+        m.timeout(scope).expect_machine();
+    });
+    assert_eq!(ctx.log, vec!["timeout"]);
+}
+
+#[test]
+fn spawn_error() {
+    let mut ctx = Context { log: Vec::new() };
+    with_scope(&mut ctx, |scope| {
+        let m = Fsm::First(Child(1, PhantomData));
+        let err = SpawnError::NoSlabSpace(FsmSeed::First(5));
+        match m.spawn_error(scope, err).expect_machine() {
+            Fsm::First(Child(5, _)) => {}
+            _ => panic!("seed is not passed to the child"),
+        }
+    });
+}
+
+#[test]
+fn seed_debug() {
+    assert_eq!(format!("{:?}", FsmSeed::<()>::Second(8)), "Second(8)");
+    assert_eq!(format!("{:?}", Seed::Main(1)), "Main(1)");
+}
+
+#[test]
+fn machine_name() {
+    let m = Concrete::Main(Child(1, PhantomData));
+    assert!(m.name().ends_with("Child<derive::Context>"), "{}", m.name());
+}
@@ -1,7 +1,6 @@
-use mio::Ready;
-use void::{Void, unreachable};
+use mio::{Ready, Token};
 
-use {Machine, Scope, Response};
+use {Machine, Scope, Response, SpawnError, Exit, Source};
 
 
 /// Composes two state machines
@@ -28,13 +27,13 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
     type Seed = Compose2Seed<AA::Seed, BB::Seed>;
 
     fn create(seed: Self::Seed, scope: &mut Scope<X>)
-        -> Response<Self, Void>
+        -> Response<Self, Self::Seed>
     {
         use Compose2::*;
         use self::Compose2Seed::*;
         match seed {
-            As(s) => AA::create(s, scope).map(A, |x| unreachable(x)),
-            Bs(s) => BB::create(s, scope).map(B, |x| unreachable(x)),
+            As(s) => AA::create(s, scope).map(A, As),
+            Bs(s) => BB::create(s, scope).map(B, Bs),
         }
     }
     fn ready(self, events: Ready, scope: &mut Scope<X>)
@@ -47,6 +46,17 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
             B(m) => { m.ready(events, scope).map(B, Bs) }
         }
     }
+    fn ready_source(self, events: Ready, source: Source,
+                    scope: &mut Scope<X>)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => { m.ready_source(events, source, scope).map(A, As) }
+            B(m) => { m.ready_source(events, source, scope).map(B, Bs) }
+        }
+    }
     fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed>
     {
         use Compose2::*;
@@ -56,6 +66,39 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
             B(m) => { m.spawned(scope).map(B, Bs) }
         }
     }
+    fn spawn_error(self, scope: &mut Scope<X>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => {
+                m.spawn_error(scope, error.map(|seed| match seed {
+                    As(s) => s,
+                    Bs(_) => unreachable!("Seed of machine A is \
+                                           returned by machine B"),
+                })).map(A, As)
+            }
+            B(m) => {
+                m.spawn_error(scope, error.map(|seed| match seed {
+                    Bs(s) => s,
+                    As(_) => unreachable!("Seed of machine B is \
+                                           returned by machine A"),
+                })).map(B, Bs)
+            }
+        }
+    }
+    fn child_exited(self, scope: &mut Scope<X>, child: Token, reason: Exit)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => { m.child_exited(scope, child, reason).map(A, As) }
+            B(m) => { m.child_exited(scope, child, reason).map(B, Bs) }
+        }
+    }
     fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         use Compose2::*;
         use self::Compose2Seed::*;
@@ -64,6 +107,20 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
             B(m) => { m.timeout(scope).map(B, Bs) }
         }
     }
+    fn describe(&self) -> Option<String> {
+        use Compose2::*;
+        match *self {
+            A(ref m) => m.describe(),
+            B(ref m) => m.describe(),
+        }
+    }
+    fn name(&self) -> &'static str {
+        use Compose2::*;
+        match *self {
+            A(ref m) => m.name(),
+            B(ref m) => m.name(),
+        }
+    }
     fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         use Compose2::*;
         use self::Compose2Seed::*;
@@ -73,3 +130,204 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use mio::Token;
+
+    use response::decompose;
+    use test_util::with_scope;
+    use {Machine, Scope, Response, EventSet, SpawnError, Time};
+    use {Loop, Config};
+    use super::Compose2;
+    use super::Compose2Seed::*;
+
+    struct Context {
+        errors: Vec<String>,
+        /// The machine stops after this number of spawn errors
+        stop_after: Option<usize>,
+    }
+
+    impl Context {
+        fn new() -> Context {
+            Context { errors: Vec::new(), stop_after: None }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Leaf(u32);
+
+    impl Machine for Leaf {
+        type Context = Context;
+        type Seed = u32;
+        fn create(seed: u32, _scope: &mut Scope<Context>)
+            -> Response<Self, u32>
+        {
+            Response::ok(Leaf(seed))
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Context>)
+            -> Response<Self, u32>
+        {
+            Response::ok(self)
+        }
+        fn spawned(self, _scope: &mut Scope<Context>) -> Response<Self, u32> {
+            Response::ok(self)
+        }
+        fn spawn_error(self, scope: &mut Scope<Context>,
+                       error: SpawnError<u32>)
+            -> Response<Self, u32>
+        {
+            match error {
+                SpawnError::NoSlabSpace(seed) => {
+                    scope.errors.push(format!("{}: no space for {}",
+                                              self.0, seed));
+                }
+                SpawnError::UserError(e) => {
+                    scope.errors.push(format!("{}: {}", self.0, e));
+                }
+            }
+            if Some(scope.errors.len()) == scope.stop_after {
+                return Response::done();
+            }
+            Response::ok(self)
+        }
+        fn timeout(self, _scope: &mut Scope<Context>) -> Response<Self, u32> {
+            let seeds = vec![self.0 + 1, self.0 + 2];
+            Response::spawn_many(self, seeds)
+        }
+        fn wakeup(self, _scope: &mut Scope<Context>) -> Response<Self, u32> {
+            Response::ok(self)
+        }
+    }
+
+    type Nested = Compose2<Leaf, Compose2<Leaf, Leaf>>;
+
+    rotor_compose!{
+        enum Fsm/Seed<Context> {
+            Plain(Leaf),
+            Pair(Compose2<Leaf, Leaf>),
+        }
+    }
+
+    fn spawn_error<M, F>(machine: M, error: SpawnError<M::Seed>, check: F)
+        -> Vec<String>
+        where M: Machine<Context=Context>, F: FnOnce(M)
+    {
+        let mut ctx = Context::new();
+        let (mach, seed, _) = with_scope(Time::zero(), &mut ctx, |scope| {
+            decompose(Token(0), machine.spawn_error(scope, error))
+        });
+        assert!(seed.is_empty());
+        check(mach.ok().expect("machine is not stopped"));
+        ctx.errors
+    }
+
+    fn timeout<M>(machine: M) -> (Option<M>, Vec<M::Seed>)
+        where M: Machine<Context=Context>
+    {
+        let (mach, seeds, _) = with_scope(Time::zero(), &mut Context::new(),
+            |scope| decompose(Token(0), machine.timeout(scope)));
+        (mach.ok(), seeds)
+    }
+
+    #[test]
+    fn spawn_many() {
+        let (mach, seeds) = timeout::<Nested>(
+            Compose2::B(Compose2::A(Leaf(1))));
+        match mach {
+            Some(Compose2::B(Compose2::A(Leaf(1)))) => {}
+            _ => panic!("wrong machine"),
+        }
+        let seeds = seeds.into_iter().map(|s| match s {
+            Bs(As(x)) => x,
+            _ => panic!("wrong seed"),
+        }).collect::<Vec<_>>();
+        assert_eq!(seeds, vec![2, 3]);
+
+        let (mach, seeds) = timeout(Fsm::Pair(Compose2::A(Leaf(4))));
+        match mach {
+            Some(Fsm::Pair(Compose2::A(Leaf(4)))) => {}
+            _ => panic!("wrong machine"),
+        }
+        let seeds = seeds.into_iter().map(|s| match s {
+            Seed::Pair(As(x)) => x,
+            _ => panic!("wrong seed"),
+        }).collect::<Vec<_>>();
+        assert_eq!(seeds, vec![5, 6]);
+    }
+
+    #[test]
+    fn compose2() {
+        let errors = spawn_error::<Compose2<Leaf, Leaf>, _>(
+            Compose2::B(Leaf(1)),
+            SpawnError::NoSlabSpace(Bs(7)), |m| match m {
+                Compose2::B(Leaf(1)) => {}
+                _ => panic!("wrong machine"),
+            });
+        assert_eq!(errors, vec!["1: no space for 7"]);
+    }
+
+    #[test]
+    fn nested_compose2() {
+        let errors = spawn_error::<Nested, _>(
+            Compose2::B(Compose2::A(Leaf(2))),
+            SpawnError::NoSlabSpace(Bs(As(8))), |m| match m {
+                Compose2::B(Compose2::A(Leaf(2))) => {}
+                _ => panic!("wrong machine"),
+            });
+        assert_eq!(errors, vec!["2: no space for 8"]);
+    }
+
+    #[test]
+    fn user_error() {
+        let errors = spawn_error::<Nested, _>(
+            Compose2::B(Compose2::B(Leaf(3))),
+            SpawnError::UserError("bad seed".into()), |_| {});
+        assert_eq!(errors, vec!["3: bad seed"]);
+    }
+
+    #[test]
+    fn macro_compose() {
+        let errors = spawn_error(Fsm::Pair(Compose2::B(Leaf(4))),
+            SpawnError::NoSlabSpace(Seed::Pair(Bs(9))), |m| match m {
+                Fsm::Pair(Compose2::B(Leaf(4))) => {}
+                _ => panic!("wrong machine"),
+            });
+        assert_eq!(errors, vec!["4: no space for 9"]);
+    }
+
+    #[test]
+    fn macro_in_compose2() {
+        let errors = spawn_error::<Compose2<Leaf, Fsm>, _>(
+            Compose2::B(Fsm::Plain(Leaf(5))),
+            SpawnError::NoSlabSpace(Bs(Seed::Plain(10))), |m| match m {
+                Compose2::B(Fsm::Plain(Leaf(5))) => {}
+                _ => panic!("wrong machine"),
+            });
+        assert_eq!(errors, vec!["5: no space for 10"]);
+    }
+
+    #[test]
+    fn spawn_error_in_loop() {
+        // The only slot of the slab is taken by the parent, so both seeds
+        // spawned on timeout fail and are routed back to the leaf
+        let mut cfg = Config::new();
+        cfg.slab_capacity(1);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Fsm::Pair(Compose2::B(Leaf(4))))
+                .deadline(scope.now())
+        }).unwrap();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let log = errors.clone();
+        lc.on_exit(move |ctx: &mut Context, _, _, _| {
+            log.borrow_mut().extend(ctx.errors.drain(..));
+        });
+        lc.run(Context { errors: Vec::new(), stop_after: Some(2) }).unwrap();
+        assert_eq!(*errors.borrow(), vec!["4: no space for 5",
+                                          "4: no space for 6"]);
+    }
+}
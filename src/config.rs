@@ -1,27 +1,185 @@
-use std::io;
+use std::io::{self, Read};
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
 use std::default::Default;
 
 use mio::deprecated::{EventLoop, EventLoopBuilder};
 
 use handler::Handler;
+use notify::{WakeupQueue, WakeupOverflow};
+use tokens::MAX_MACHINES;
+use trace::{Tracer, TraceBuffer};
 use {Machine, Slab};
 
 
+quick_error! {
+    /// Error of the loop configuration
+    #[derive(Debug)]
+    pub enum ConfigError {
+        /// The value of the option is out of the allowed range
+        Invalid(option: &'static str, reason: &'static str) {
+            description("invalid configuration option")
+            display("invalid option {}: {}", option, reason)
+        }
+        /// The value of the option can't be parsed
+        Parse(option: String, value: String) {
+            description("can't parse configuration option")
+            display("can't parse value {:?} of option {}", value, option)
+        }
+        /// There is no such option
+        UnknownOption(option: String) {
+            description("unknown configuration option")
+            display("unknown configuration option {:?}", option)
+        }
+        /// The option is known, but the loop can't support it
+        Unsupported(option: &'static str, reason: &'static str) {
+            description("unsupported configuration option")
+            display("option {} is not supported: {}", option, reason)
+        }
+        /// The line of the configuration file is not `key = value`
+        Syntax(line: usize) {
+            description("syntax error in configuration file")
+            display("syntax error in configuration file at line {}", line)
+        }
+        /// Error reading configuration file
+        Io(err: io::Error) {
+            description("error reading configuration file")
+            display("error reading configuration file: {}", err)
+            from()
+        }
+    }
+}
+
+quick_error! {
+    /// Error creating the loop
+    #[derive(Debug)]
+    pub enum LoopError {
+        /// The configuration is invalid
+        Config(err: ConfigError) {
+            description("invalid loop configuration")
+            display("invalid loop configuration: {}", err)
+            from()
+        }
+        /// Error creating the event loop (e.g. out of file descriptors)
+        Io(err: io::Error) {
+            description("error creating event loop")
+            display("error creating event loop: {}", err)
+            from()
+        }
+    }
+}
+
+impl From<LoopError> for io::Error {
+    fn from(err: LoopError) -> io::Error {
+        match err {
+            LoopError::Config(e) => {
+                io::Error::new(io::ErrorKind::InvalidInput, e)
+            }
+            LoopError::Io(e) => e,
+        }
+    }
+}
+
+/// Options which are often expected from the loop, but can't be supported
+///
+/// They are rejected by `Config::set` with `ConfigError::Unsupported`
+/// rather than `ConfigError::UnknownOption`, so the reason is reported.
+const UNSUPPORTED: &'static [(&'static str, &'static str)] = &[
+    ("threads", "every loop runs in a single thread, \
+        run a loop per thread instead"),
+    ("events_per_poll", "mio's EventLoop always polls up to 1024 events"),
+    ("shutdown_deadline_ms", "the loop is stopped at the end of \
+        the current iteration without waiting for state machines"),
+];
+
+/// Names of the options accepted by `Config::set`
+const OPTIONS: &'static [&'static str] = &[
+    "slab_capacity",
+    "catch_panics",
+    "trace",
+    "trace_buffer",
+    "notify_capacity",
+    "messages_per_tick",
+    "timer_tick_ms",
+    "timer_wheel_size",
+    "timer_capacity",
+    "wakeup_queue_bound",
+    "wakeup_overflow",
+];
+
 /// Event loop configuration
 ///
-/// The structure currently embeds mio configuration too
+/// The structure currently embeds mio configuration too. Options of mio
+/// are better set with the methods of this structure, as they are
+/// validated and applied on top of `mio()` builder.
+///
+/// Setters return the config, so they may be chained:
+///
+/// ```ignore
+/// let mut cfg = Config::new();
+/// cfg.slab_capacity(100000).timer_tick(Duration::from_millis(10));
+/// try!(cfg.load_env("MYAPP_LOOP_"));
+/// let lc = try!(Loop::new(&cfg));
+/// ```
+///
+/// Options are validated when the loop is created, or by
+/// `Config::validate`.
+///
+/// There are no options for the number of threads, the number of events
+/// per poll and the shutdown deadline, as the loop can't support them:
+/// every loop runs in a single thread (run a loop per thread to use more
+/// cores), mio's `EventLoop` always polls up to 1024 events at once, and
+/// `Scope::shutdown_loop` stops the loop at the end of the current
+/// iteration without waiting for state machines. Setting `threads`,
+/// `events_per_poll` or `shutdown_deadline_ms` from a file or environment
+/// fails with `ConfigError::Unsupported`.
 #[derive(Debug, Clone)]
 pub struct Config {
     mio: EventLoopBuilder,
     slab_capacity: usize,
+    catch_panics: bool,
+    trace: bool,
+    trace_buffer: usize,
+    notify_capacity: Option<usize>,
+    messages_per_tick: Option<usize>,
+    timer_tick: Option<Duration>,
+    timer_wheel_size: Option<usize>,
+    timer_capacity: Option<usize>,
+    wakeup_queue_bound: Option<usize>,
+    wakeup_overflow: WakeupOverflow,
 }
 
 impl Default for Config {
     fn default() -> Config {
-        Config {
-            mio: Default::default(),
-            slab_capacity: 4096,
-        }
+        Config::new()
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(option: &str, value: &str)
+    -> Result<T, ConfigError>
+{
+    value.parse().map_err(|_| {
+        ConfigError::Parse(option.to_string(), value.to_string())
+    })
+}
+
+fn parse_bool(option: &str, value: &str) -> Result<bool, ConfigError> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(ConfigError::Parse(option.to_string(), value.to_string())),
+    }
+}
+
+fn positive(option: &'static str, value: Option<usize>)
+    -> Result<(), ConfigError>
+{
+    if value == Some(0) {
+        Err(ConfigError::Invalid(option, "must be positive"))
+    } else {
+        Ok(())
     }
 }
 
@@ -31,6 +189,16 @@ impl Config {
         Config {
             mio: EventLoopBuilder::new(),
             slab_capacity: 4096,
+            catch_panics: false,
+            trace: false,
+            trace_buffer: 0,
+            notify_capacity: None,
+            messages_per_tick: None,
+            timer_tick: None,
+            timer_wheel_size: None,
+            timer_capacity: None,
+            wakeup_queue_bound: None,
+            wakeup_overflow: WakeupOverflow::Fail,
         }
     }
     /// A mutable reference for ``mio::EventLoopBuilder``
@@ -42,8 +210,230 @@ impl Config {
     /// This limits the number of state machines that application is able
     /// to create. Consequently this limits the number of connections that
     /// server is able to establish.
-    pub fn slab_capacity(&mut self, capacity: usize) {
+    pub fn slab_capacity(&mut self, capacity: usize) -> &mut Config {
         self.slab_capacity = capacity;
+        self
+    }
+    /// Isolate panics in state machines
+    ///
+    /// When enabled, a panic in any action of a state machine removes only
+    /// that state machine from the loop (the panic is logged and counted,
+    /// see `LoopInstance::panic_counter`), instead of tearing down the whole
+    /// loop. Note that the context may be left in an inconsistent state by
+    /// the panicked state machine.
+    ///
+    /// Disabled by default.
+    pub fn catch_panics(&mut self, enable: bool) -> &mut Config {
+        self.catch_panics = enable;
+        self
+    }
+    /// Trace every action dispatched to state machines
+    ///
+    /// Every record contains the token, the action, the kind of the
+    /// response, deadline changes and the duration of the action. Records
+    /// are logged at the trace level with the `rotor::trace` target.
+    ///
+    /// Disabled by default.
+    pub fn trace(&mut self, enable: bool) -> &mut Config {
+        self.trace = enable;
+        self
+    }
+    /// Keep the last `capacity` trace records in memory
+    ///
+    /// Enables tracing if `capacity` is not zero. The buffer is available
+    /// via `LoopInstance::trace_buffer`. The buffer is also logged when a
+    /// state machine panics, whether `catch_panics` is enabled or not.
+    pub fn trace_buffer(&mut self, capacity: usize) -> &mut Config {
+        self.trace_buffer = capacity;
+        self
+    }
+    /// The capacity of the mio's notification channel
+    ///
+    /// Wakeups are put into the wakeup queue (see `wakeup_queue_bound`),
+    /// and the channel is only used to notify the loop. Default is 4096.
+    pub fn notify_capacity(&mut self, capacity: usize) -> &mut Config {
+        self.notify_capacity = Some(capacity);
+        self
+    }
+    /// The maximum number of wakeups processed in a single loop iteration
+    ///
+    /// The rest is processed on the next iteration, after I/O events.
+    /// Default is 256.
+    pub fn messages_per_tick(&mut self, messages: usize) -> &mut Config {
+        self.messages_per_tick = Some(messages);
+        self
+    }
+    /// The granularity of the timer
+    ///
+    /// Deadlines are rounded up to the tick. Must be at least a
+    /// millisecond. Default is 100 ms. Ignored for deadlines with the
+    /// `precise_time` feature.
+    pub fn timer_tick(&mut self, tick: Duration) -> &mut Config {
+        self.timer_tick = Some(tick);
+        self
+    }
+    /// The number of slots in the timer wheel (rounded to a power of two)
+    ///
+    /// Default is 1024.
+    pub fn timer_wheel_size(&mut self, size: usize) -> &mut Config {
+        self.timer_wheel_size = Some(size);
+        self
+    }
+    /// The maximum number of pending deadlines
+    ///
+    /// Every state machine with a deadline takes one. Default is 65536.
+    pub fn timer_capacity(&mut self, capacity: usize) -> &mut Config {
+        self.timer_capacity = Some(capacity);
+        self
+    }
+    /// The number of state machines which may have a pending wakeup
+    ///
+    /// Repeated wakeups of the same state machine are coalesced, so this
+    /// limits the number of distinct state machines only. What to do when
+    /// the limit is reached is set by `wakeup_overflow`. Default is the
+    /// slab capacity, so the queue never overflows.
+    pub fn wakeup_queue_bound(&mut self, bound: usize) -> &mut Config {
+        self.wakeup_queue_bound = Some(bound);
+        self
+    }
+    /// What to do on a wakeup when the wakeup queue is full
+    ///
+    /// Default is `WakeupOverflow::Fail`.
+    pub fn wakeup_overflow(&mut self, policy: WakeupOverflow)
+        -> &mut Config
+    {
+        self.wakeup_overflow = policy;
+        self
+    }
+    /// Checks that the options are in the allowed range
+    ///
+    /// Called when the loop is created.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.slab_capacity == 0 {
+            return Err(ConfigError::Invalid("slab_capacity",
+                                            "must be positive"));
+        }
+        if self.slab_capacity > MAX_MACHINES {
+            return Err(ConfigError::Invalid("slab_capacity",
+                "is larger than the number of tokens available"));
+        }
+        try!(positive("notify_capacity", self.notify_capacity));
+        try!(positive("messages_per_tick", self.messages_per_tick));
+        try!(positive("timer_wheel_size", self.timer_wheel_size));
+        try!(positive("timer_capacity", self.timer_capacity));
+        try!(positive("wakeup_queue_bound", self.wakeup_queue_bound));
+        if let Some(tick) = self.timer_tick {
+            if tick < Duration::from_millis(1) {
+                return Err(ConfigError::Invalid("timer_tick",
+                    "must be at least a millisecond"));
+            }
+        }
+        Ok(())
+    }
+    /// Sets the option by name, the value is parsed from a string
+    ///
+    /// The names are the same as the names of the methods, except the
+    /// `timer_tick_ms`, which is in milliseconds. Boolean values are
+    /// `true`/`false`, `yes`/`no`, `on`/`off` or `1`/`0`. The overflow
+    /// policy is `fail` or `drop`.
+    pub fn set(&mut self, option: &str, value: &str)
+        -> Result<&mut Config, ConfigError>
+    {
+        let value = value.trim();
+        match option {
+            "slab_capacity" => {
+                self.slab_capacity(try!(parse(option, value)));
+            }
+            "catch_panics" => {
+                self.catch_panics(try!(parse_bool(option, value)));
+            }
+            "trace" => {
+                self.trace(try!(parse_bool(option, value)));
+            }
+            "trace_buffer" => {
+                self.trace_buffer(try!(parse(option, value)));
+            }
+            "notify_capacity" => {
+                self.notify_capacity(try!(parse(option, value)));
+            }
+            "messages_per_tick" => {
+                self.messages_per_tick(try!(parse(option, value)));
+            }
+            "timer_tick_ms" => {
+                let ms = try!(parse(option, value));
+                self.timer_tick(Duration::from_millis(ms));
+            }
+            "timer_wheel_size" => {
+                self.timer_wheel_size(try!(parse(option, value)));
+            }
+            "timer_capacity" => {
+                self.timer_capacity(try!(parse(option, value)));
+            }
+            "wakeup_queue_bound" => {
+                self.wakeup_queue_bound(try!(parse(option, value)));
+            }
+            "wakeup_overflow" => {
+                self.wakeup_overflow(match value {
+                    "fail" => WakeupOverflow::Fail,
+                    "drop" => WakeupOverflow::Drop,
+                    _ => return Err(ConfigError::Parse(option.to_string(),
+                                                       value.to_string())),
+                });
+            }
+            _ => {
+                for &(name, reason) in UNSUPPORTED {
+                    if name == option {
+                        return Err(ConfigError::Unsupported(name, reason));
+                    }
+                }
+                return Err(ConfigError::UnknownOption(option.to_string()));
+            }
+        }
+        Ok(self)
+    }
+    /// Sets options from environment variables
+    ///
+    /// The name of the variable is the `prefix` followed by the name of the
+    /// option in upper case, e.g. `ROTOR_SLAB_CAPACITY` for the `ROTOR_`
+    /// prefix. See `set` for the names of the options.
+    pub fn load_env(&mut self, prefix: &str) -> Result<&mut Config, ConfigError>
+    {
+        for option in OPTIONS {
+            let name = format!("{}{}", prefix, option.to_uppercase());
+            if let Ok(value) = env::var(&name) {
+                try!(self.set(option, &value));
+            }
+        }
+        Ok(self)
+    }
+    /// Sets options from `key = value` lines
+    ///
+    /// Empty lines and lines starting with `#` are skipped. See `set` for
+    /// the names of the options.
+    pub fn load_str(&mut self, text: &str) -> Result<&mut Config, ConfigError>
+    {
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let idx = match line.find('=') {
+                Some(idx) => idx,
+                None => return Err(ConfigError::Syntax(num + 1)),
+            };
+            try!(self.set(line[..idx].trim(), &line[idx+1..]));
+        }
+        Ok(self)
+    }
+    /// Sets options from the file of `key = value` lines
+    ///
+    /// See `load_str` for the format.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P)
+        -> Result<&mut Config, ConfigError>
+    {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+        self.load_str(&text)
     }
 }
 
@@ -55,8 +445,147 @@ pub fn create_slab<M:Sized>(cfg: &Config) -> Slab<M> {
     Slab::with_capacity(cfg.slab_capacity)
 }
 
+pub fn create_queue(cfg: &Config) -> WakeupQueue {
+    WakeupQueue::new(cfg.slab_capacity,
+        cfg.wakeup_queue_bound.unwrap_or(cfg.slab_capacity),
+        cfg.wakeup_overflow)
+}
+
+pub fn catch_panics(cfg: &Config) -> bool {
+    cfg.catch_panics
+}
+
+pub fn tracer(cfg: &Config) -> Option<Tracer> {
+    if cfg.trace_buffer > 0 {
+        Some(Tracer::new(Some(TraceBuffer::new(cfg.trace_buffer))))
+    } else if cfg.trace {
+        Some(Tracer::new(None))
+    } else {
+        None
+    }
+}
+
 pub fn create_loop<M: Machine>(cfg: &Config)
-    -> Result<EventLoop<Handler<M>>, io::Error>
+    -> Result<EventLoop<Handler<M>>, LoopError>
 {
-    cfg.mio.clone().build()
+    try!(cfg.validate());
+    let mut mio = cfg.mio.clone();
+    if let Some(capacity) = cfg.notify_capacity {
+        mio.notify_capacity(capacity);
+    }
+    if let Some(messages) = cfg.messages_per_tick {
+        mio.messages_per_tick(messages);
+    }
+    if let Some(tick) = cfg.timer_tick {
+        mio.timer_tick(tick);
+    }
+    if let Some(size) = cfg.timer_wheel_size {
+        mio.timer_wheel_size(size);
+    }
+    if let Some(capacity) = cfg.timer_capacity {
+        mio.timer_capacity(capacity);
+    }
+    Ok(try!(mio.build()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io;
+    use std::time::Duration;
+
+    use void::Void;
+
+    use notify::WakeupOverflow;
+    use {Loop, Machine, Scope, Response, EventSet};
+    use super::{Config, ConfigError, LoopError};
+
+    struct Fsm;
+
+    impl Machine for Fsm {
+        type Context = ();
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<()>) -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn load() {
+        let mut cfg = Config::new();
+        cfg.load_str("# loop options\n\
+                      slab_capacity = 100\n\
+                      \n\
+                      catch_panics=yes\n\
+                      timer_tick_ms = 10\n\
+                      wakeup_overflow = drop\n").unwrap();
+        assert_eq!(cfg.slab_capacity, 100);
+        assert!(cfg.catch_panics);
+        assert_eq!(cfg.timer_tick, Some(Duration::from_millis(10)));
+        assert_eq!(cfg.wakeup_overflow, WakeupOverflow::Drop);
+        env::set_var("ROTOR_TEST_MESSAGES_PER_TICK", "16");
+        cfg.load_env("ROTOR_TEST_").unwrap();
+        assert_eq!(cfg.messages_per_tick, Some(16));
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        let mut cfg = Config::new();
+        match cfg.load_str("slab_capacity = 10\nbad line") {
+            Err(ConfigError::Syntax(2)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        match cfg.set("slab_capacity", "many") {
+            Err(ConfigError::Parse(..)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        match cfg.set("no_such_option", "4") {
+            Err(ConfigError::UnknownOption(..)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        match cfg.load_str("threads = 4") {
+            Err(ConfigError::Unsupported("threads", _)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        cfg.slab_capacity(0);
+        match cfg.validate() {
+            Err(ConfigError::Invalid("slab_capacity", _)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        cfg.slab_capacity(10).timer_tick(Duration::new(0, 1000));
+        match cfg.validate() {
+            Err(ConfigError::Invalid("timer_tick", _)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_loop() {
+        let mut cfg = Config::new();
+        cfg.timer_capacity(0);
+        match Loop::<Fsm>::new(&cfg) {
+            Err(LoopError::Config(ConfigError::Invalid("timer_capacity", _)))
+            => {}
+            Err(e) => panic!("unexpected {:?}", e),
+            Ok(_) => panic!("loop is created"),
+        }
+        let err: io::Error = Loop::<Fsm>::new(&cfg).err().unwrap().into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
 }
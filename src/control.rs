@@ -0,0 +1,387 @@
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use mio::deprecated::{TryRead, TryWrite};
+use mio::deprecated::unix::{UnixListener, UnixStream};
+use void::Void;
+
+use loop_time::millis_between;
+use {Machine, Scope, Response, EventSet, PollOpt, GenericScope};
+
+/// Maximum length of the command line, longer lines close the connection
+const MAX_LINE: usize = 1024;
+
+/// Commands are not read while this much of the output is not sent yet
+const MAX_OUTPUT: usize = 65536;
+
+/// Delay before accepting again after an error (e.g. too many open files)
+const ACCEPT_RETRY: u64 = 100;
+
+const HELP: &'static str = "\
+    list      -- list state machines with their tokens and deadlines\n\
+    counters  -- show the number of actions dispatched\n\
+    shutdown  -- shutdown the event loop\n\
+    help      -- this help\n";
+
+
+/// A control socket for a running loop
+///
+/// The state machine listens on a Unix socket and answers simple line
+/// commands (use `socat - UNIX-CONNECT:/path/to/socket` to connect):
+///
+/// * `list` -- lists state machines in the loop: token, time to the
+///   deadline and the result of `Machine::describe`
+/// * `counters` -- the number of actions dispatched, see `Counters`
+/// * `shutdown` -- shuts down the loop
+/// * `help` -- lists commands
+///
+/// Every response is terminated by an empty line.
+///
+/// This is a regular state machine, so it can be put next to other
+/// machines of the application:
+///
+/// ```ignore
+/// rotor_compose!{
+///     pub enum Fsm/Seed<Context> {
+///         Http(HttpServer),
+///         Control(Control<Context>),
+///     }
+/// }
+///
+/// loop_creator.add_machine_with(|scope| {
+///     Control::new("/run/app/control.sock", scope).wrap(Fsm::Control)
+/// }).unwrap();
+/// ```
+///
+/// Note: connections to the control socket are the state machines too, so
+/// they are listed by the `list` command.
+pub struct Control<C> {
+    state: State,
+    phantom: PhantomData<*const C>,
+}
+
+enum State {
+    Listener(UnixListener),
+    Connection(Connection),
+}
+
+struct Connection {
+    sock: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<C> Control<C> {
+    fn wrap(state: State) -> Control<C> {
+        Control {
+            state: state,
+            phantom: PhantomData,
+        }
+    }
+    /// Creates a control socket at `path`
+    ///
+    /// The file at the path must not exist
+    pub fn new<P: AsRef<Path>, S: GenericScope>(path: P, scope: &mut S)
+        -> Response<Control<C>, Void>
+    {
+        match UnixListener::bind(path.as_ref()) {
+            Ok(sock) => Control::from_listener(sock, scope),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    /// Creates a control machine from an already bound listener
+    pub fn from_listener<S: GenericScope>(sock: UnixListener, scope: &mut S)
+        -> Response<Control<C>, Void>
+    {
+        match scope.register(&sock, EventSet::readable(), PollOpt::edge()) {
+            Ok(()) => Response::ok(Control::wrap(State::Listener(sock))),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+}
+
+fn accept<C>(sock: UnixListener, scope: &mut Scope<C>)
+    -> Response<Control<C>, UnixStream>
+{
+    match sock.accept() {
+        Ok(conn) => {
+            Response::spawn(Control::wrap(State::Listener(sock)), conn)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Response::ok(Control::wrap(State::Listener(sock)))
+        }
+        Err(e) => {
+            // The listener is edge-triggered, so there will be no event for
+            // the connections which are already pending
+            warn!("Error accepting control connection: {}. \
+                Retrying in {}ms", e, ACCEPT_RETRY);
+            Response::ok(Control::wrap(State::Listener(sock)))
+                .deadline(scope.now() + Duration::from_millis(ACCEPT_RETRY))
+        }
+    }
+}
+
+fn execute<C>(command: &str, output: &mut Vec<u8>, scope: &mut Scope<C>) {
+    match command {
+        "list" => {
+            let now = scope.now();
+            for info in scope.machines() {
+                let deadline = match info.deadline {
+                    Some(time) => {
+                        format!("{}ms", millis_between(now, time))
+                    }
+                    None => "-".to_string(),
+                };
+                output.extend(format!("{} {} {}\n", info.token.0, deadline,
+                    info.description.as_ref().map(|x| &x[..]).unwrap_or(""))
+                    .as_bytes());
+            }
+        }
+        "counters" => {
+            let c = scope.counters();
+            output.extend(format!("create {}\nready {}\nspawned {}\n\
+                spawn_error {}\nchild_exited {}\ntimeout {}\nwakeup {}\n",
+                c.create, c.ready, c.spawned, c.spawn_error,
+                c.child_exited, c.timeout, c.wakeup).as_bytes());
+        }
+        "shutdown" => {
+            output.extend(b"shutting down\n");
+            scope.shutdown_loop();
+        }
+        "help" => output.extend(HELP.as_bytes()),
+        "" => return,
+        _ => {
+            output.extend(format!("error: unknown command {:?}\n", command)
+                .as_bytes());
+        }
+    }
+    output.push(b'\n');
+}
+
+impl Connection {
+    fn execute_lines<C>(&mut self, scope: &mut Scope<C>) {
+        while let Some(end) = self.input.iter().position(|&x| x == b'\n') {
+            let rest = self.input.split_off(end+1);
+            let line = mem::replace(&mut self.input, rest);
+            let command = String::from_utf8_lossy(&line);
+            execute(command.trim(), &mut self.output, scope);
+        }
+    }
+    fn ready<C>(mut self, events: EventSet, scope: &mut Scope<C>)
+        -> Option<Connection>
+    {
+        if events.is_readable() {
+            let mut buf = [0u8; 1024];
+            // A client which doesn't read the responses is not allowed to
+            // grow the buffer indefinitely
+            while self.output.len() < MAX_OUTPUT {
+                match self.sock.try_read(&mut buf) {
+                    Ok(Some(0)) => return None,
+                    Ok(Some(x)) => self.input.extend(&buf[..x]),
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("Error reading control connection: {}", e);
+                        return None;
+                    }
+                }
+                self.execute_lines(scope);
+                if self.input.len() > MAX_LINE {
+                    debug!("Control command is too long");
+                    return None;
+                }
+            }
+        }
+        if !self.output.is_empty() {
+            match self.sock.try_write(&self.output) {
+                Ok(Some(x)) => {
+                    self.output.drain(..x);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Error writing control connection: {}", e);
+                    return None;
+                }
+            }
+        }
+        let mut interest = EventSet::empty();
+        if self.output.len() < MAX_OUTPUT {
+            interest.insert(EventSet::readable());
+        }
+        if !self.output.is_empty() {
+            interest.insert(EventSet::writable());
+        }
+        match scope.reregister(&self.sock, interest, PollOpt::level()) {
+            Ok(()) => Some(self),
+            Err(e) => {
+                debug!("Error registering control connection: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<C> Machine for Control<C> {
+    type Context = C;
+    type Seed = UnixStream;
+
+    fn create(sock: UnixStream, scope: &mut Scope<C>)
+        -> Response<Self, UnixStream>
+    {
+        match scope.register(&sock, EventSet::readable(), PollOpt::level()) {
+            Ok(()) => Response::ok(Control::wrap(State::Connection(
+                Connection {
+                    sock: sock,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                }))),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, UnixStream>
+    {
+        match self.state {
+            State::Listener(sock) => accept(sock, scope),
+            State::Connection(conn) => match conn.ready(events, scope) {
+                Some(conn) => {
+                    Response::ok(Control::wrap(State::Connection(conn)))
+                }
+                None => Response::done(),
+            },
+        }
+    }
+    fn spawned(self, scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        match self.state {
+            State::Listener(sock) => accept(sock, scope),
+            State::Connection(_) => unreachable!(),
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<C>,
+                   error: ::SpawnError<UnixStream>)
+        -> Response<Self, UnixStream>
+    {
+        // The connection is closed when the seed is dropped
+        warn!("Can't accept control connection: {}", error);
+        match self.state {
+            State::Listener(sock) => accept(sock, scope),
+            State::Connection(_) => unreachable!(),
+        }
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        match self.state {
+            State::Listener(sock) => accept(sock, scope),
+            State::Connection(_) => Response::ok(self),
+        }
+    }
+    fn describe(&self) -> Option<String> {
+        match self.state {
+            State::Listener(_) => Some("control socket".to_string()),
+            State::Connection(_) => Some("control connection".to_string()),
+        }
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        Response::ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::net;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::thread;
+
+    use mio::deprecated::unix::UnixStream;
+
+    use test_util::with_scope;
+    use {Loop, Config, Time, EventSet, PollOpt};
+    use super::{Control, Connection, MAX_OUTPUT};
+
+    #[test]
+    fn commands() {
+        let path = env::temp_dir().join(
+            format!("rotor-control-{}.sock", ::std::process::id()));
+        fs::remove_file(&path).ok();
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|scope| {
+            Control::<()>::new(&path, scope)
+        }).unwrap();
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut sock = net::UnixStream::connect(&client_path).unwrap();
+            sock.write_all(b"list\nbad\nshutdown\n").unwrap();
+            let mut result = String::new();
+            sock.read_to_string(&mut result).unwrap();
+            result
+        });
+        lc.run(()).unwrap();
+        fs::remove_file(&path).ok();
+        // loop is shut down, so the connection is closed
+        assert_eq!(client.join().unwrap(),
+            "0 - control socket\n\n\
+             error: unknown command \"bad\"\n\n\
+             shutting down\n\n");
+    }
+
+    #[test]
+    fn long_line() {
+        let path = env::temp_dir().join(
+            format!("rotor-control-long-{}.sock", ::std::process::id()));
+        fs::remove_file(&path).ok();
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|scope| {
+            Control::<()>::new(&path, scope)
+        }).unwrap();
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut sock = net::UnixStream::connect(&client_path).unwrap();
+            // The connection is closed, but the listener keeps working
+            let mut result = Vec::new();
+            for _ in 0..4 {
+                if sock.write_all(&[b'x'; 1024]).is_err() {
+                    break;
+                }
+            }
+            sock.read_to_end(&mut result).ok();
+            let mut sock = net::UnixStream::connect(&client_path).unwrap();
+            sock.write_all(b"shutdown\n").unwrap();
+            sock.read_to_end(&mut result).unwrap();
+            String::from_utf8(result).unwrap()
+        });
+        lc.run(()).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(client.join().unwrap(), "shutting down\n\n");
+    }
+
+    #[test]
+    fn output_limit() {
+        let (mut client, server) = net::UnixStream::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+        let sock = unsafe { UnixStream::from_raw_fd(server.into_raw_fd()) };
+        // The client sends commands but never reads the responses
+        let commands = b"help\n".repeat(200);
+        while client.write(&commands).is_ok() {}
+        with_scope(Time::zero(), &mut (), |scope| {
+            scope.register(&sock, EventSet::readable(), PollOpt::level())
+                .unwrap();
+            let mut conn = Some(Connection {
+                sock: sock,
+                input: Vec::new(),
+                output: Vec::new(),
+            });
+            for _ in 0..100 {
+                let c = conn.take().unwrap();
+                conn = c.ready(EventSet::readable(), scope);
+            }
+            let conn = conn.expect("connection is not closed");
+            // Commands of a single read may be executed over the limit
+            assert!(conn.output.len() < 2 * MAX_OUTPUT);
+        });
+    }
+}
@@ -1,13 +1,24 @@
 use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
 
+use mio::Token;
 use mio::deprecated::EventLoop;
 use void::{Void, unreachable};
 
-use config::{create_slab, create_loop};
-use handler::{Handler, create_handler, set_timeout_opt};
-use scope::{early_scope, EarlyScope, Scope};
-use {Machine, Config, SpawnError, Timeout, Time, Response, Slab};
-use SpawnError::NoSlabSpace;
+use config::{create_slab, create_loop, create_queue, catch_panics, tracer};
+use config::LoopError;
+use handler::{Handler, Entry, ExitHandler, create_handler, set_timeout_opt};
+use handler::top_level_entry;
+use trace::{Tracer, TraceBuffer};
+use introspect::{MachineInfo, Counters};
+use loop_api::Api;
+use timer::Timers;
+use registry::Registry;
+use scope::{queued_early_scope, EarlyScope, Scope};
+use notify::WakeupQueue;
+use {Machine, Config, SpawnError, Response, Slab, Exit};
+use SpawnError::{NoSlabSpace, UserError};
 use response::decompose;
 
 
@@ -54,8 +65,14 @@ use response::decompose;
 ///
 /// [the guide]: http://rotor.readthedocs.org/en/latest/loop_init.html
 pub struct LoopCreator<M: Machine> {
-    slab: Slab<(Option<(Timeout, Time)>, M)>,
+    slab: Slab<Entry<M>>,
     mio: EventLoop<Handler<M>>,
+    catch_panics: bool,
+    on_exit: Option<ExitHandler<M::Context>>,
+    tracer: Option<Tracer>,
+    timers: Timers,
+    registry: Registry,
+    queue: Arc<WakeupQueue>,
 }
 /// Second stage of loop creation
 ///
@@ -68,44 +85,81 @@ pub struct LoopInstance<M: Machine> {
 }
 
 impl<M: Machine> LoopCreator<M> {
-    pub fn new(cfg: &Config) -> Result<LoopCreator<M>, io::Error> {
+    /// Creates the loop with the configuration
+    ///
+    /// Fails with `LoopError::Config` if the configuration is invalid (see
+    /// `Config::validate`).
+    pub fn new(cfg: &Config) -> Result<LoopCreator<M>, LoopError> {
         let slab = create_slab(&cfg);
-        let eloop = try!(create_loop(&cfg));
+        let mut eloop = try!(create_loop(&cfg));
+        let timers = try!(Timers::new());
+        try!(timers.register(&mut eloop));
         Ok(LoopCreator {
             slab: slab,
             mio: eloop,
+            catch_panics: catch_panics(&cfg),
+            on_exit: None,
+            tracer: tracer(&cfg),
+            timers: timers,
+            registry: Registry::new(),
+            queue: Arc::new(create_queue(&cfg)),
         })
     }
 
+    /// Sets a callback which is called when any state machine exits
+    ///
+    /// The callback receives the context, the token and the name of the
+    /// state machine (see `Machine::name`) and the reason of the exit (including the error
+    /// returned by `Response::error`). Use it to count, report or alert on
+    /// errors, regardless of the `log_errors` feature.
+    ///
+    /// Note: it's also called for the children linked to the parent, before
+    /// the `Machine::child_exited` of the parent.
+    pub fn on_exit<F>(&mut self, callback: F)
+        where F: FnMut(&mut M::Context, Token, &'static str, &Exit) + 'static
+    {
+        self.on_exit = Some(Box::new(callback));
+    }
+
     pub fn add_machine_with<F>(&mut self, fun: F) -> Result<(), SpawnError<()>>
         where F: FnOnce(&mut EarlyScope) -> Response<M, Void>
     {
         let ref mut chan = self.mio.channel();
-        let ref mut mio = self.mio;
-        let res = self.slab.vacant_entry().map(|entry| {
-            let token = entry.index();
-            entry.insert({
-              let ref mut scope = early_scope(token, chan, mio);
-              let (mach, void, timeout) =  decompose(token, fun(scope));
-              void.map(|x| unreachable(x));
-              let m = mach.expect("You can't return Response::done() \
-                    from Machine::create() until new release of slab crate. \
-                    (requires insert_with_opt)");
-              let to = set_timeout_opt(timeout, scope);
-              (to, m)
-            })
-        });
-        if res.is_some() {
-            Ok(())
-        } else {
-            // TODO(tailhook) propagate error from state machine construtor
-            Err(NoSlabSpace(()))
+        let ref mut api = Api::new(&mut self.mio, &mut self.timers,
+                                   &mut self.registry);
+        let entry = match self.slab.vacant_entry() {
+            Some(entry) => entry,
+            None => return Err(NoSlabSpace(())),
+        };
+        let token = entry.index();
+        let ref mut scope = queued_early_scope(token, chan, &self.queue,
+                                               api);
+        let (mach, void, timeout) =  decompose(token, fun(scope));
+        for x in void { unreachable(x) }
+        match mach {
+            Ok(m) => {
+                let to = set_timeout_opt(timeout, scope);
+                entry.insert(top_level_entry(to, m));
+                Ok(())
+            }
+            // The machine decided to stop right away, it's not an error
+            Err(None) => {
+                scope.deregister_all();
+                Ok(())
+            }
+            Err(Some(e)) => {
+                scope.deregister_all();
+                Err(UserError(e))
+            }
         }
     }
 
     pub fn instantiate(self, context: M::Context) -> LoopInstance<M> {
-        let LoopCreator { slab, mio } = self;
-        let handler = create_handler(slab, context, mio.channel());
+        let LoopCreator { slab, mio, catch_panics, on_exit, tracer,
+                          timers, registry, queue } = self;
+        let handler = create_handler(slab, context, mio.channel(), queue,
+                                     catch_panics, on_exit, tracer, timers,
+                                     registry);
         LoopInstance { mio: mio, handler: handler }
     }
 
@@ -122,9 +176,49 @@ impl<M: Machine> LoopInstance<M> {
         self.handler.add_machine_with(&mut self.mio, fun)
     }
 
+    /// Sets a callback which is called when any state machine exits
+    ///
+    /// See `LoopCreator::on_exit` for more information.
+    pub fn on_exit<F>(&mut self, callback: F)
+        where F: FnMut(&mut M::Context, Token, &'static str, &Exit) + 'static
+    {
+        self.handler.on_exit(Box::new(callback));
+    }
+
+    /// Returns the information about every state machine in the loop
+    ///
+    /// Each item contains the token, the pending deadline and the result of
+    /// `Machine::describe` of the state machine.
+    pub fn machines(&self) -> Vec<MachineInfo> {
+        self.handler.machines()
+    }
+
+    /// Returns the number of actions dispatched so far
+    pub fn counters(&self) -> Counters {
+        self.handler.counters()
+    }
+
+    /// Returns the buffer of the last trace records
+    ///
+    /// Returns `None` unless enabled by `Config::trace_buffer`. The buffer
+    /// may be cloned and dumped from a panic hook or another thread.
+    pub fn trace_buffer(&self) -> Option<TraceBuffer> {
+        self.handler.trace_buffer()
+    }
+
+    /// Returns a counter of panics caught in state machines
+    ///
+    /// The counter may be sent to another thread for monitoring. Panics
+    /// are only caught if enabled by `Config::catch_panics`.
+    pub fn panic_counter(&self) -> Arc<AtomicUsize> {
+        self.handler.panic_counter()
+    }
+
     pub fn run(mut self) -> Result<(), io::Error> {
         let ref mut handler = self.handler;
         let ref mut mio = self.mio;
+        // Deadlines of the machines added before the loop is started
+        handler.arm_timers();
         mio.run(handler)
     }
 }
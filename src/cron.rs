@@ -0,0 +1,266 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+quick_error! {
+    /// Error parsing a cron expression
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum CronError {
+        /// The expression doesn't have exactly five fields
+        FieldCount(count: usize) {
+            description("cron expression must have five fields")
+            display("cron expression must have five fields, got {}", count)
+        }
+        /// The field can't be parsed or a value is out of range
+        InvalidField(field: &'static str, value: String) {
+            description("invalid field of cron expression")
+            display("invalid {} field {:?} of cron expression", field, value)
+        }
+    }
+}
+
+/// A parsed cron expression
+///
+/// The expression has five fields: minute (0-59), hour (0-23), day of
+/// month (1-31), month (1-12) and day of week (0-7, both 0 and 7 are
+/// Sunday). Every field is either `*`, a number, a range `1-5`, or a list
+/// of them separated by commas `1,3,10-12`. A star or a range may have a
+/// step: `*/15`, `9-17/2`. Names of months and days are not supported.
+///
+/// Like in the classic cron, if both day of month and day of week are
+/// restricted (i.e. don't start with `*`), the time matches when either of
+/// them matches.
+///
+/// Shortcuts `@yearly` (or `@annually`), `@monthly`, `@weekly`, `@daily`
+/// (or `@midnight`) and `@hourly` are supported too.
+///
+/// All times are in UTC. The next time is used along with `WallDeadline`:
+///
+/// ```ignore
+/// let cron = "0 3 * * *".parse::<Cron>().unwrap();
+/// let at = WallDeadline::new(cron.next_after(SystemTime::now()).unwrap());
+/// Response::ok(Job(at)).deadline(at.deadline(scope.now()))
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// How far to look for the next match, the expression like `0 0 30 2 *`
+/// never matches
+const MAX_YEARS: i64 = 8;
+
+fn parse_field(name: &'static str, text: &str, min: u32, max: u32)
+    -> Result<u64, CronError>
+{
+    let err = || CronError::InvalidField(name, text.to_string());
+    let number = |s: &str| s.parse::<u32>().map_err(|_| err());
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], Some(try!(number(&part[idx+1..])))),
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(idx) = range.find('-') {
+            (try!(number(&range[..idx])), try!(number(&range[idx+1..])))
+        } else {
+            let start = try!(number(range));
+            (start, if step.is_some() { max } else { start })
+        };
+        if start < min || end > max || start > end || step == Some(0) {
+            return Err(err());
+        }
+        let mut value = start;
+        while value <= end {
+            mask |= 1 << value;
+            value = match value.checked_add(step.unwrap_or(1)) {
+                Some(value) => value,
+                None => break,
+            };
+        }
+    }
+    Ok(mask)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // The algorithm is from http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Cron {
+    /// Parses the cron expression, same as `str::parse`
+    pub fn new(expression: &str) -> Result<Cron, CronError> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(CronError::FieldCount(fields.len()));
+        }
+        let weekdays = try!(parse_field("day of week", fields[4], 0, 7));
+        Ok(Cron {
+            minutes: try!(parse_field("minute", fields[0], 0, 59)),
+            hours: try!(parse_field("hour", fields[1], 0, 23)),
+            days: try!(parse_field("day of month", fields[2], 1, 31)),
+            months: try!(parse_field("month", fields[3], 1, 12)),
+            // Sunday is both 0 and 7
+            weekdays: (weekdays | weekdays >> 7) & 0x7F,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+    fn day_matches(&self, day: u32, weekday: u32) -> bool {
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+    /// Returns the first matching time strictly after `time`
+    ///
+    /// Returns `None` if the expression doesn't match any time in the next
+    /// few years (e.g. 30th of February), or if `time` is before the Unix
+    /// epoch.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(dur) => dur.as_secs() as i64,
+            Err(_) => return None,
+        };
+        let mut minute = secs / 60 + 1;
+        let limit = civil_from_days(secs / 86400).0 + MAX_YEARS;
+        loop {
+            let days = minute / 1440;
+            let (year, month, day) = civil_from_days(days);
+            if year > limit {
+                return None;
+            }
+            if self.months & (1 << month) == 0 {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                minute = days_from_civil(year, month, 1) * 1440;
+                continue;
+            }
+            // 1970-01-01 is Thursday
+            let weekday = ((days + 4) % 7) as u32;
+            if !self.day_matches(day, weekday) {
+                minute = (days + 1) * 1440;
+                continue;
+            }
+            if self.hours & (1 << (minute % 1440 / 60)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) == 0 {
+                minute += 1;
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(minute as u64 * 60));
+        }
+    }
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+    fn from_str(s: &str) -> Result<Cron, CronError> {
+        Cron::new(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{Cron, CronError, days_from_civil, civil_from_days};
+
+    fn utc(year: i64, month: u32, day: u32, hour: u64, minute: u64)
+        -> SystemTime
+    {
+        let days = days_from_civil(year, month, day) as u64;
+        UNIX_EPOCH + Duration::from_secs(days*86400 + hour*3600 + minute*60)
+    }
+
+    fn next(expr: &str, after: SystemTime) -> Option<SystemTime> {
+        expr.parse::<Cron>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn civil() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        for &days in &[0, 59, 365, 10000, 20000, 50000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn next_time() {
+        let start = utc(2024, 2, 28, 3, 0);  // Wednesday
+        assert_eq!(next("0 3 * * *", start), Some(utc(2024, 2, 29, 3, 0)));
+        assert_eq!(next("*/15 * * * *", start), Some(utc(2024, 2, 28, 3, 15)));
+        assert_eq!(next("@hourly", start), Some(utc(2024, 2, 28, 4, 0)));
+        assert_eq!(next("30 9-17/4 * * 1-5", start),
+                   Some(utc(2024, 2, 28, 9, 30)));
+        assert_eq!(next("0 0 * * 7", start), Some(utc(2024, 3, 3, 0, 0)));
+        assert_eq!(next("0 0 1,15 * 1", start), Some(utc(2024, 3, 1, 0, 0)));
+        assert_eq!(next("0 0 29 2 *", start + Duration::from_secs(86400)),
+                   Some(utc(2028, 2, 29, 0, 0)));
+        assert_eq!(next("@yearly", start), Some(utc(2025, 1, 1, 0, 0)));
+        assert_eq!(next("0 0 30 2 *", start), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!("* * *".parse::<Cron>(), Err(CronError::FieldCount(3)));
+        assert_eq!("60 * * * *".parse::<Cron>(),
+            Err(CronError::InvalidField("minute", "60".to_string())));
+        assert_eq!("* * 0 * *".parse::<Cron>(),
+            Err(CronError::InvalidField("day of month", "0".to_string())));
+        assert_eq!("*/0 * * * *".parse::<Cron>(),
+            Err(CronError::InvalidField("minute", "*/0".to_string())));
+        assert_eq!("* * * jan *".parse::<Cron>(),
+            Err(CronError::InvalidField("month", "jan".to_string())));
+        // a huge step means the single value
+        assert_eq!("59/4294967295 * * * *".parse::<Cron>(),
+                   "59 * * * *".parse::<Cron>());
+    }
+}
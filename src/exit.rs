@@ -0,0 +1,57 @@
+use std::fmt;
+use std::error::Error;
+
+
+/// The reason a state machine has exited
+///
+/// This value is passed to `Machine::child_exited` of the parent, for
+/// the children spawned with `Response::linked()`, and to the exit handler
+/// set by `LoopCreator::on_exit` for every state machine.
+pub enum Exit {
+    /// The state machine returned `Response::done()`
+    Done,
+    /// The state machine returned `Response::error(..)`
+    Error(Box<Error>),
+    /// The state machine panicked
+    ///
+    /// This is only reported when panics are caught, see
+    /// `Config::catch_panics`. The value is the panic message.
+    Panic(String),
+}
+
+impl Exit {
+    /// Returns true if the state machine exited normally
+    pub fn is_done(&self) -> bool {
+        match *self {
+            Exit::Done => true,
+            _ => false,
+        }
+    }
+    /// Returns an error if the state machine exited with an error
+    pub fn error(&self) -> Option<&Error> {
+        match *self {
+            Exit::Error(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exit::Done => write!(fmt, "done"),
+            Exit::Error(ref e) => write!(fmt, "error: {}", e),
+            Exit::Panic(ref msg) => write!(fmt, "panic: {}", msg),
+        }
+    }
+}
+
+impl fmt::Debug for Exit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exit::Done => write!(fmt, "Done"),
+            Exit::Error(ref e) => write!(fmt, "Error({:?})", e),
+            Exit::Panic(ref msg) => write!(fmt, "Panic({:?})", msg),
+        }
+    }
+}
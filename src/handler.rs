@@ -1,16 +1,30 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::collections::{HashSet, VecDeque};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
 use Slab;
 use mio::{self, Token, Ready};
-use mio::timer::Timeout;
 use mio::deprecated::{EventLoop, Sender};
 use void::{Void, unreachable};
 
-use scope::scope;
-use {SpawnError, Scope, Response, Machine, Time, GenericScope};
-use SpawnError::{NoSlabSpace};
-use loop_time::{make_time, mio_timeout_ms};
-use response::{decompose};
+use scope::{serial_scope, machine_scope, DeadlineScope};
+use loop_api::{Api, LoopApi};
+use timer::{Timers, TimerHandle};
+use registry::Registry;
+use tokens;
+use notify::{WakeupQueue, WakeupStatus, AckSender};
+use introspect::{Introspect, MachineInfo, Counters, count};
+use {SpawnError, Scope, Response, Machine, Time, Exit};
+use Action;
+use SpawnError::{NoSlabSpace, UserError};
+use layer::panic_message;
+use trace::{Tracer, TraceBuffer, TraceRecord};
+use loop_time::make_time;
+use response::{decompose, is_linked, response_kind, response_deadline};
 
 
 #[doc(hidden)]
@@ -21,6 +35,10 @@ pub enum Timeo {
 #[doc(hidden)]
 pub enum Notify {
     Fsm(Token),
+    /// Wake up the state machine with the generation and acknowledge
+    Ack(Token, u64, AckSender),
+    /// There are state machines to wake up in the `WakeupQueue`
+    Queue,
 }
 
 
@@ -43,29 +61,163 @@ pub enum Notify {
 /// ```
 pub struct Handler<M: Machine>
 {
-    slab: Slab<(Option<(Timeout, Time)>, M)>,
+    slab: Slab<Entry<M>>,
     context: M::Context,
     channel: Sender<Notify>,
+    queue: Arc<WakeupQueue>,
     start_time: Instant,
+    catch_panics: bool,
+    panics: Arc<AtomicUsize>,
+    serial: u64,
+    on_exit: Option<ExitHandler<M::Context>>,
+    tracer: Option<Tracer>,
+    counters: Cell<Counters>,
+    timers: Timers,
+    registry: Registry,
 }
 
-pub fn create_handler<M: Machine>(slab: Slab<(Option<(Timeout, Time)>, M)>,
-    context: M::Context, channel: Sender<Notify>)
+/// A callback which is called when any state machine exits
+///
+/// Arguments are: the context, the token and the name of the state
+/// machine (see `Machine::name`), and the reason of the exit
+pub type ExitHandler<C> = Box<FnMut(&mut C, Token, &'static str, &Exit)>;
+
+/// A state machine in the slab along with its bookkeeping
+#[doc(hidden)]
+pub struct Entry<M> {
+    timeout: Option<(TimerHandle, Time)>,
+    machine: M,
+    /// Unique number of the machine, to tell it apart from the machine
+    /// which reuses the same token later
+    serial: u64,
+    /// The token and serial of the parent, if the machine is linked to it
+    parent: Option<(Token, u64)>,
+}
+
+/// The state machine exited: parent token and serial (if linked), the token
+/// of the state machine itself and the reason
+type Exited = (Option<(Token, u64)>, Token, &'static str, Exit);
+
+/// Makes an entry for a state machine added before the loop is started
+///
+/// Such machines have zero serial, all the ones created later have
+/// bigger numbers
+pub fn top_level_entry<M>(timeout: Option<(TimerHandle, Time)>, machine: M)
+    -> Entry<M>
+{
+    Entry {
+        timeout: timeout,
+        machine: machine,
+        serial: 0,
+        parent: None,
+    }
+}
+
+pub fn create_handler<M: Machine>(slab: Slab<Entry<M>>,
+    context: M::Context, channel: Sender<Notify>,
+    queue: Arc<WakeupQueue>, catch_panics: bool,
+    on_exit: Option<ExitHandler<M::Context>>, tracer: Option<Tracer>,
+    timers: Timers, registry: Registry)
     -> Handler<M>
 {
     Handler {
         slab: slab,
         context: context,
         channel: channel,
+        queue: queue,
         start_time: Instant::now(),
+        catch_panics: catch_panics,
+        panics: Arc::new(AtomicUsize::new(0)),
+        serial: 0,
+        on_exit: on_exit,
+        tracer: tracer,
+        counters: Cell::new(Counters::default()),
+        timers: timers,
+        registry: registry,
+    }
+}
+
+/// Loop-wide options which are used when dispatching an action
+struct Hooks<'a> {
+    panics: Option<&'a AtomicUsize>,
+    tracer: Option<&'a Tracer>,
+    counters: &'a Cell<Counters>,
+}
+
+impl<'a> Hooks<'a> {
+    fn count(&self, action: Action) {
+        let mut counters = self.counters.get();
+        count(&mut counters, action);
+        self.counters.set(counters);
+    }
+    /// Calls the function, if `panics` is set, catches a panic in it
+    ///
+    /// When panic is caught it is logged and counted, and `Err` with the
+    /// panic message is returned. Otherwise the trace buffer (if any) is
+    /// logged and the panic is propagated.
+    fn guard<R, F>(&self, token: Token, fun: F) -> Result<R, String>
+        where F: FnOnce() -> R
+    {
+        match self.panics {
+            None => match self.buffer() {
+                None => Ok(fun()),
+                Some(buffer) => {
+                    match catch_unwind(AssertUnwindSafe(fun)) {
+                        Ok(result) => Ok(result),
+                        Err(payload) => {
+                            error!("State machine {:?} panicked: {}",
+                                token, panic_message(&payload));
+                            error!("Last actions before the panic:\n{}",
+                                buffer.dump());
+                            resume_unwind(payload)
+                        }
+                    }
+                }
+            },
+            Some(counter) => {
+                catch_unwind(AssertUnwindSafe(fun)).map_err(|payload| {
+                    let msg = panic_message(&payload).to_string();
+                    error!("State machine {:?} panicked: {}", token, msg);
+                    if let Some(buffer) = self.buffer() {
+                        error!("Last actions before the panic:\n{}",
+                            buffer.dump());
+                    }
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    msg
+                })
+            }
+        }
+    }
+    fn buffer(&self) -> Option<&TraceBuffer> {
+        self.tracer.and_then(|t| t.buffer())
+    }
+    /// Returns the start time of the action if tracing is enabled
+    fn start(&self) -> Option<Instant> {
+        self.tracer.map(|_| Instant::now())
+    }
+    fn trace(&self, start: Option<Instant>, time: Time, token: Token,
+        action: Action, response: &'static str,
+        old_deadline: Option<Time>, deadline: Option<Time>)
+    {
+        if let (Some(tracer), Some(start)) = (self.tracer, start) {
+            tracer.record(TraceRecord {
+                time: time,
+                token: token,
+                action: action,
+                response: response,
+                old_deadline: old_deadline,
+                deadline: deadline,
+                duration: start.elapsed(),
+            });
+        }
     }
 }
-pub fn set_timeout_opt<S: GenericScope>(option: Option<Time>, scope: &mut S)
-    -> Option<(Timeout, Time)>
+
+pub fn set_timeout_opt<S: DeadlineScope>(option: Option<Time>, scope: &mut S)
+    -> Option<(TimerHandle, Time)>
 {
     option.map(|new_ts| {
-        let ms = mio_timeout_ms(scope.now(), new_ts);
-        let tok = scope.timeout_ms(ms)
+        let tok = scope.set_deadline(new_ts)
             .expect("Can't insert a timeout. You need to \
                      increase the timer capacity");
         (tok, new_ts)
@@ -73,91 +225,337 @@ pub fn set_timeout_opt<S: GenericScope>(option: Option<Time>, scope: &mut S)
 }
 
 fn replacer<C, M, N>(token: Token,
-    resp: Response<M, N>, old_timeo: Option<(Timeout, Time)>,
-    scope: &mut Scope<C>, creator: &mut Option<N>)
-    -> Option<(Option<(Timeout, Time)>, M)>
+    resp: Response<M, N>, old_timeo: Option<(TimerHandle, Time)>,
+    scope: &mut Scope<C>, creator: &mut Vec<(N, bool)>)
+    -> Result<(Option<(TimerHandle, Time)>, M), Exit>
 {
+    let linked = is_linked(&resp);
     let (mach, new, newtime) = decompose(token, resp);
     let rtime = if newtime != old_timeo.clone().map(|(_, x)| x) {
         if let Some((tok, _)) = old_timeo {
-            scope.clear_timeout(tok);
+            scope.clear_deadline(tok);
         }
         set_timeout_opt(newtime, scope)
     } else {
         old_timeo
     };
-    *creator = new;
-    mach.map(|m| (rtime, m)).ok() // the error is already logged in decompose()
+    creator.extend(new.into_iter().map(|seed| (seed, linked)));
+    match mach {
+        Ok(m) => Ok((rtime, m)),
+        // the error is already logged in decompose()
+        Err(Some(e)) => Err(Exit::Error(e)),
+        Err(None) => Err(Exit::Done),
+    }
+}
+
+/// The parts of the loop which are needed to create a scope
+struct Env<'a, M: Machine + 'a> {
+    time: Time,
+    context: &'a mut M::Context,
+    channel: &'a mut Sender<Notify>,
+    queue: &'a Arc<WakeupQueue>,
+    api: Api<'a, M>,
+}
+
+impl<'a, M: Machine + 'a> Env<'a, M> {
+    fn scope<'b>(&'b mut self, token: Token, serial: u64)
+        -> Scope<'b, M::Context>
+    {
+        serial_scope(self.time, token, serial, &mut *self.context,
+                     &mut *self.channel, self.queue, &mut self.api)
+    }
+    fn machine_scope<'b>(&'b mut self, token: Token, serial: u64,
+        machines: &'b Introspect)
+        -> Scope<'b, M::Context>
+    {
+        machine_scope(self.time, token, serial, &mut *self.context,
+                      &mut *self.channel, self.queue, &mut self.api,
+                      machines)
+    }
+}
+
+/// A view of the loop which is available to the state machines
+struct View<'a, M: 'a> {
+    slab: &'a Slab<Entry<M>>,
+    counters: Counters,
+}
+
+impl<'a, M: Machine> Introspect for View<'a, M> {
+    fn machines(&self) -> Vec<MachineInfo> {
+        (0..self.slab.capacity()).filter_map(|idx| {
+            let token = Token(idx);
+            self.slab.get(token).map(|entry| MachineInfo {
+                token: token,
+                deadline: entry.timeout.as_ref().map(|&(_, time)| time),
+                description: entry.machine.describe(),
+            })
+        }).collect()
+    }
+    fn counters(&self) -> Counters {
+        self.counters
+    }
 }
 
-fn replace<M, F>(slab: &mut Slab<(Option<(Timeout, Time)>, M)>, token: Token, fun: F, scope: &mut Scope<M::Context>, creator: &mut Option<M::Seed>)
+/// Dispatches action to the state machine
+///
+/// Returns `false` if the state machine is not in the slab after the action
+fn replace<M, F>(slab: &mut Slab<Entry<M>>, token: Token, action: Action,
+    fun: F, env: &mut Env<M>, hooks: &Hooks,
+    creator: &mut Vec<(M::Seed, bool)>, exits: &mut VecDeque<Exited>)
+    -> bool
     where M: Machine,
           F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
 {
-    slab.entry(token).and_then(|entry| {
-      let (timeo, m) = entry.remove();
-      replacer(token, fun(m, scope), timeo, scope, creator)
-    }).map(|new_val|{
-      let entry = slab.vacant_entry().expect("The entry was just freed.");
-      entry.insert(new_val)
-    });
+    let Entry { timeout, machine, serial, parent } = match slab.entry(token) {
+        Some(entry) => entry.remove(),
+        // Spurious events are ok in mio
+        None => return false,
+    };
+    hooks.count(action);
+    let name = machine.name();
+    let result = {
+        let view = View { slab: &*slab, counters: hooks.counters.get() };
+        let ref mut scope = env.machine_scope(token, serial, &view);
+        let start = hooks.start();
+        let old_deadline = timeout.as_ref().map(|&(_, time)| time);
+        let mut kind = "panic";
+        let result = match hooks.guard(token, || fun(machine, scope)) {
+            Ok(resp) => {
+                kind = response_kind(&resp);
+                replacer(token, resp, timeout, scope, creator)
+            }
+            Err(msg) => {
+                // The machine is lost, only the timeout is left to clean up
+                if let Some((tok, _)) = timeout {
+                    scope.clear_deadline(tok);
+                }
+                Err(Exit::Panic(msg))
+            }
+        };
+        let deadline = match result {
+            Ok((Some((_, time)), _)) => Some(time),
+            _ => None,
+        };
+        hooks.trace(start, scope.now(), token, action, kind,
+                    old_deadline, deadline);
+        result
+    };
+    match result {
+        Ok((timeout, machine)) => {
+            let entry = slab.vacant_entry().expect("The entry was just freed.");
+            entry.insert(Entry {
+                timeout: timeout,
+                machine: machine,
+                serial: serial,
+                parent: parent,
+            });
+            true
+        }
+        Err(reason) => {
+            env.api.release(token);
+            exits.push_back((parent, token, name, reason));
+            false
+        }
+    }
+}
+
+/// Creates a state machine from a seed and puts it into the slab
+///
+/// Returns the token of the new state machine and the seeds it wants to
+/// spawn, or `None` if the machine returned `Response::done()`.
+fn create<M: Machine>(slab: &mut Slab<Entry<M>>,
+    seed: M::Seed, serial: u64, parent: Option<(Token, u64)>,
+    env: &mut Env<M>, hooks: &Hooks)
+    -> Result<Option<(Token, Vec<(M::Seed, bool)>)>, SpawnError<M::Seed>>
+{
+    let entry = match slab.vacant_entry() {
+        Some(entry) => entry,
+        None => return Err(NoSlabSpace(seed)),
+    };
+    let token = entry.index();
+    let ref mut scope = env.scope(token, serial);
+    hooks.count(Action::Create);
+    let start = hooks.start();
+    match hooks.guard(token, || M::create(seed, scope)) {
+        Ok(res) => {
+            let linked = is_linked(&res);
+            hooks.trace(start, scope.now(), token, Action::Create,
+                response_kind(&res), None, response_deadline(&res));
+            let (mach, newm, newtime) = decompose(token, res);
+            match mach {
+                Ok(m) => {
+                    let timepair = set_timeout_opt(newtime, scope);
+                    entry.insert(Entry {
+                        timeout: timepair,
+                        machine: m,
+                        serial: serial,
+                        parent: parent,
+                    });
+                    let newm = newm.into_iter()
+                        .map(|seed| (seed, linked)).collect();
+                    Ok(Some((token, newm)))
+                }
+                Err(None) => {
+                    scope.deregister_all();
+                    Ok(None)
+                }
+                Err(Some(e)) => {
+                    scope.deregister_all();
+                    Err(UserError(e))
+                }
+            }
+        }
+        Err(msg) => {
+            scope.deregister_all();
+            hooks.trace(start, scope.now(), token, Action::Create,
+                "panic", None, None);
+            let err: Box<Error> = format!("state machine panicked \
+                in create: {}", msg).into();
+            Err(UserError(err))
+        }
+    }
 }
 
 fn machine_loop<M, F>(handler: &mut Handler<M>,
-    eloop: &mut EventLoop<Handler<M>>, token: Token, fun: F)
+    eloop: &mut EventLoop<Handler<M>>, token: Token, action: Action, fun: F)
     where M: Machine,
           F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
 {
-    let time = handler.loop_time();
-    let ref mut context = handler.context;
-    let ref mut channel = handler.channel;
-    let mut creator = None;
-    {
-        let ref mut scope = scope(time, token, context, channel, eloop);
-        replace(&mut handler.slab, token, fun, scope, &mut creator)
-        // Spurious events are ok in mio
+    let ref mut env = Env {
+        time: handler.loop_time(),
+        context: &mut handler.context,
+        channel: &mut handler.channel,
+        queue: &handler.queue,
+        api: Api::new(eloop, &mut handler.timers, &mut handler.registry),
+    };
+    let ref mut slab = handler.slab;
+    let ref mut serial = handler.serial;
+    let ref mut on_exit = handler.on_exit;
+    let ref hooks = Hooks {
+        panics: if handler.catch_panics {
+            Some(&*handler.panics)
+        } else {
+            None
+        },
+        tracer: handler.tracer.as_ref(),
+        counters: &handler.counters,
+    };
+    let mut creator = Vec::new();
+    let mut exits = VecDeque::new();
+    let alive = replace(slab, token, action, fun, env, hooks,
+                        &mut creator, &mut exits);
+    // Every item is a parent, whether it's still alive, and the seeds it
+    // has spawned. Newly created machines may spawn their own children, so
+    // they are put into the same queue.
+    let mut queue = VecDeque::new();
+    if !creator.is_empty() {
+        queue.push_back((token, alive, creator));
     }
-    while let Some(new) = creator.take() {
-        let mut new = Some(new);
-        let ins = handler.slab.vacant_entry().map(|entry| {
-            let token = entry.index();
-            entry.insert({
-              let ref mut scope = scope(time, token, context, channel, eloop);
-              let (mach, newm, newtime) = decompose(token,
-                  M::create(new.take().unwrap(), scope));
-              newm.map(|x| unreachable(x));
-              let m = mach.expect("You can't return Response::done() \
-                    from Machine::create() until new release of slab crate. \
-                    (requires insert_with_opt)");
-              let timepair = newtime.map(|new_ts| {
-                  let ms = mio_timeout_ms(scope.now(), new_ts);
-                  let tok = scope.timeout_ms(ms)
-                      .expect("Can't insert a timeout. You need to \
-                               increase the timer capacity");
-                  (tok, new_ts)
-              });
-              (timepair, m)
-            })
-        }).is_none();
-        if ins {
-            // TODO(tailhook) process other errors here, when they can
-            // be returned from handler
-            let err = NoSlabSpace(new.expect("expecting seed is still here"));
-
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            replace(&mut handler.slab, token, |m, scope| m.spawn_error(scope, err), scope, &mut creator)
+    loop {
+        if let Some((parent, mut alive, seeds)) = queue.pop_front() {
+            let mut spawned = false;
+            let mut creator = Vec::new();
+            for (seed, linked) in seeds {
+                let link = if linked && alive {
+                    slab.get(parent).map(|entry| (parent, entry.serial))
+                } else {
+                    None
+                };
+                *serial += 1;
+                let err = match create(slab, seed, *serial, link, env, hooks) {
+                    Ok(Some((child, grandchildren))) => {
+                        spawned = true;
+                        if !grandchildren.is_empty() {
+                            queue.push_back((child, true, grandchildren));
+                        }
+                        continue;
+                    }
+                    Ok(None) => {
+                        spawned = true;
+                        continue;
+                    }
+                    Err(err) => err,
+                };
+                // The token might have been reused by a child if the parent
+                // exited in the middle of spawning, so we check `alive` flag
+                if alive {
+                    alive = replace(slab, parent, Action::SpawnError,
+                        |m, scope| m.spawn_error(scope, err),
+                        env, hooks, &mut creator, &mut exits);
+                } else {
+                    warn!("Error spawning state machine after {:?} \
+                        exited: {}", parent, err);
+                }
+            }
+            if spawned && alive {
+                alive = replace(slab, parent, Action::Spawned,
+                    |m, scope| m.spawned(scope),
+                    env, hooks, &mut creator, &mut exits);
+            }
+            if !creator.is_empty() {
+                queue.push_back((parent, alive, creator));
+            }
+        } else if let Some((link, child, name, reason)) = exits.pop_front() {
+            if let Some(ref mut on_exit) = *on_exit {
+                on_exit(env.context, child, name, &reason);
+            }
+            let (parent, pserial) = match link {
+                Some(link) => link,
+                None => continue,
+            };
+            // The parent might have exited, and its token reused already
+            if slab.get(parent).map(|e| e.serial != pserial).unwrap_or(true) {
+                continue;
+            }
+            let mut creator = Vec::new();
+            let alive = replace(slab, parent, Action::ChildExited(child),
+                |m, scope| m.child_exited(scope, child, reason),
+                env, hooks, &mut creator, &mut exits);
+            if !creator.is_empty() {
+                queue.push_back((parent, alive, creator));
+            }
         } else {
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            replace(&mut handler.slab, token, |m, scope| m.spawned(scope), scope, &mut creator)
+            break;
         }
     }
-    if handler.slab.is_empty() {
-        eloop.shutdown();
+    if slab.is_empty() {
+        env.api.eloop.shutdown();
     }
 }
 
 impl<M: Machine> Handler<M>
 {
+    /// Returns a counter of panics caught in state machines
+    ///
+    /// Panics are caught only if enabled in `Config::catch_panics`
+    pub fn panic_counter(&self) -> Arc<AtomicUsize> {
+        self.panics.clone()
+    }
+    /// Sets a callback which is called when any state machine exits
+    pub fn on_exit(&mut self, callback: ExitHandler<M::Context>) {
+        self.on_exit = Some(callback);
+    }
+    /// Returns the information about every state machine in the loop
+    pub fn machines(&self) -> Vec<MachineInfo> {
+        View { slab: &self.slab, counters: self.counters.get() }.machines()
+    }
+    /// Returns the number of actions dispatched so far
+    pub fn counters(&self) -> Counters {
+        self.counters.get()
+    }
+    /// Returns the generation of the state machine with the token
+    fn generation(&self, token: Token) -> Option<u64> {
+        self.slab.get(token).map(|entry| tokens::generation(entry.serial))
+    }
+    /// Returns the buffer of the trace records if enabled in the config
+    pub fn trace_buffer(&self) -> Option<TraceBuffer> {
+        self.tracer.as_ref().and_then(|t| t.buffer()).cloned()
+    }
+    /// Arms the timer for the earliest deadline (`precise_time` mode only)
+    pub fn arm_timers(&mut self) {
+        let now = self.loop_time();
+        self.timers.arm(now);
+    }
     pub fn loop_time(&self) -> Time {
         let now = Instant::now();
         return make_time(self.start_time, now);
@@ -169,28 +567,50 @@ impl<M: Machine> Handler<M>
         let time = self.loop_time();
         let ref mut context = self.context;
         let ref mut channel = self.channel;
-        let res = self.slab.vacant_entry().map(|entry| {
-          let token = entry.index();
-          entry.insert({
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            let (mach, void, timeout) =  decompose(token, fun(scope));
-            void.map(|x| unreachable(x));
-            let m = mach.expect("You can't return Response::done() or \
-                  Reponse::error() from Machine::create() until new release \
-                  of slab crate. (requires insert_with_opt)");
-            let to = set_timeout_opt(timeout, scope);
-            (to, m)
-          });
-        });
-        if res.is_some() {
-            Ok(())
-        } else {
-            // TODO(tailhook) propagate error from state machine construtor
-            Err(NoSlabSpace(()))
+        let ref mut api = Api::new(eloop, &mut self.timers,
+                                   &mut self.registry);
+        let entry = match self.slab.vacant_entry() {
+            Some(entry) => entry,
+            None => return Err(NoSlabSpace(())),
+        };
+        let token = entry.index();
+        self.serial += 1;
+        let ref mut scope = serial_scope(time, token, self.serial,
+                                         context, channel, &self.queue,
+                                         api);
+        let (mach, void, timeout) =  decompose(token, fun(scope));
+        for x in void { unreachable(x) }
+        match mach {
+            Ok(m) => {
+                let to = set_timeout_opt(timeout, scope);
+                entry.insert(Entry {
+                    timeout: to,
+                    machine: m,
+                    serial: self.serial,
+                    parent: None,
+                });
+                Ok(())
+            }
+            Err(None) => {
+                scope.deregister_all();
+                Ok(())
+            }
+            Err(Some(e)) => {
+                scope.deregister_all();
+                Err(UserError(e))
+            }
         }
     }
 }
 
+impl<M: Machine> Drop for Handler<M> {
+    fn drop(&mut self) {
+        // Notifiers may outlive the loop, don't queue wakeups and
+        // acknowledgements nobody will process
+        self.queue.close();
+    }
+}
+
 impl<M: Machine> mio::deprecated::Handler for Handler<M>
 {
     type Message = Notify;
@@ -198,24 +618,888 @@ impl<M: Machine> mio::deprecated::Handler for Handler<M>
     fn ready<'x>(&mut self, eloop: &'x mut EventLoop<Self>,
         token: Token, events: Ready)
     {
-        machine_loop(self, eloop, token, |m, scope| { m.ready(events, scope) })
+        #[cfg(feature="precise_time")]
+        {
+            if token == ::timer::TIMER_TOKEN {
+                let now = self.loop_time();
+                for token in self.timers.expired(now) {
+                    machine_loop(self, eloop, token, Action::Timeout,
+                        |m, scope| { m.timeout(scope) })
+                }
+                return;
+            }
+        }
+        let (token, source, generation) = tokens::decode(token);
+        let stale = self.slab.get(token)
+            .map(|entry| tokens::generation(entry.serial) != generation)
+            .unwrap_or(false);
+        if stale {
+            // The I/O object was registered by the state machine which has
+            // exited already, and the token is reused by another one
+            debug!("Stale event {:?} for {:?} ignored", events, token);
+            return;
+        }
+        machine_loop(self, eloop, token, Action::Ready(events),
+            |m, scope| { m.ready_source(events, source, scope) })
     }
 
     fn notify(&mut self, eloop: &mut EventLoop<Self>, msg: Notify) {
         match msg {
             Notify::Fsm(token) => {
-                machine_loop(self, eloop, token,
+                machine_loop(self, eloop, token, Action::Wakeup,
                     |m, scope| { m.wakeup(scope) })
             }
+            Notify::Ack(token, generation, ack) => {
+                let alive = self.generation(token) == Some(generation);
+                machine_loop(self, eloop, token, Action::Wakeup,
+                    |m, scope| { m.wakeup(scope) });
+                ack.resolve(if alive {
+                    WakeupStatus::Dispatched
+                } else {
+                    WakeupStatus::Vacant
+                });
+            }
+            Notify::Queue => {
+                let (queued, acks) = self.queue.take();
+                let mut dispatched = HashSet::new();
+                for token in queued {
+                    // Cleared before the action, so the machine may be
+                    // woken up again while processing this wakeup
+                    if self.queue.clear(token) {
+                        if let Some(generation) = self.generation(token) {
+                            dispatched.insert((token, generation));
+                        }
+                        machine_loop(self, eloop, token, Action::Wakeup,
+                            |m, scope| { m.wakeup(scope) })
+                    }
+                }
+                // The wakeup of the acknowledged token may be coalesced with
+                // the one processed earlier, then it's dispatched already if
+                // the state machine is still there
+                for (token, generation, ack) in acks {
+                    if dispatched.contains(&(token, generation)) ||
+                        self.generation(token) == Some(generation)
+                    {
+                        ack.resolve(WakeupStatus::Dispatched);
+                    } else {
+                        ack.resolve(WakeupStatus::Vacant);
+                    }
+                }
+            }
         }
     }
 
     fn timeout(&mut self, eloop: &mut EventLoop<Self>, timeo: Timeo) {
         match timeo {
             Timeo::Fsm(token) => {
-                machine_loop(self, eloop, token,
+                machine_loop(self, eloop, token, Action::Timeout,
                     |m, scope| { m.timeout(scope) })
             }
         }
     }
+
+    fn tick(&mut self, _eloop: &mut EventLoop<Self>) {
+        self.arm_timers();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::atomic::Ordering;
+    use std::io::Write;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use mio::Token;
+    use mio::deprecated::unix::{pipe, PipeReader, PipeWriter};
+    use void::Void;
+
+    use layer::panic_message;
+    use test_util::with_scope;
+    use {Time, Machine, Scope, Response, EventSet, Loop, Config, SpawnError, Exit};
+    use {PollOpt, Source, WakeupError, WakeupStatus, Compose2};
+
+    enum Fsm {
+        Panic,
+        Survive,
+    }
+
+    impl Machine for Fsm {
+        type Context = Vec<&'static str>;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn timeout(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            match self {
+                Fsm::Panic => panic!("test panic"),
+                Fsm::Survive => {
+                    scope.push("survived");
+                    Response::done()
+                }
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn catch_panics() {
+        let mut cfg = Config::new();
+        cfg.catch_panics(true);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Fsm::Panic).deadline(scope.now())
+        }).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Fsm::Survive)
+                .deadline(scope.now() + Duration::from_millis(10))
+        }).unwrap();
+        let mut inst = lc.instantiate(Vec::new());
+        let counter = inst.panic_counter();
+        inst.run().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Debug)]
+    enum Burst {
+        Parent,
+        Child(u32),
+    }
+
+    impl Machine for Burst {
+        type Context = Rc<RefCell<Vec<String>>>;
+        type Seed = u32;
+        fn create(seed: u32, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            Response::ok(Burst::Child(seed)).deadline(scope.now())
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+        fn spawned(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            scope.borrow_mut().push(format!("spawned"));
+            Response::done()
+        }
+        fn spawn_error(self, scope: &mut Scope<Self::Context>,
+                       error: SpawnError<u32>)
+            -> Response<Self, u32>
+        {
+            match error {
+                SpawnError::NoSlabSpace(x) => {
+                    scope.borrow_mut().push(format!("no {}", x))
+                }
+                SpawnError::UserError(_) => unreachable!(),
+            }
+            Response::ok(self)
+        }
+        fn timeout(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            match self {
+                Burst::Parent => Response::spawn_many(self, 0..5),
+                Burst::Child(x) => {
+                    scope.borrow_mut().push(format!("child {}", x));
+                    Response::done()
+                }
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn spawn_many() {
+        let mut cfg = Config::new();
+        cfg.slab_capacity(4);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Burst::Parent).deadline(scope.now())
+        }).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        // order of timeouts for children is undefined
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, vec!["child 0", "child 1", "child 2",
+                             "no 3", "no 4", "spawned"]);
+    }
+
+    enum Tree {
+        Root,
+        Node(u32),
+    }
+
+    impl Machine for Tree {
+        type Context = Rc<RefCell<Vec<String>>>;
+        type Seed = u32;
+        fn create(seed: u32, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            match seed {
+                0 => Response::error("rejected".into()),
+                1 => {
+                    scope.borrow_mut().push(format!("leaf"));
+                    Response::done()
+                }
+                x => {
+                    scope.borrow_mut().push(format!("node {}", x));
+                    Response::spawn(Tree::Node(x), x - 1)
+                }
+            }
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+        fn spawned(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            match self {
+                Tree::Root => scope.borrow_mut().push(format!("spawned root")),
+                Tree::Node(x) => {
+                    scope.borrow_mut().push(format!("spawned {}", x))
+                }
+            }
+            Response::done()
+        }
+        fn spawn_error(self, scope: &mut Scope<Self::Context>,
+                       error: SpawnError<u32>)
+            -> Response<Self, u32>
+        {
+            scope.borrow_mut().push(format!("error: {}", error));
+            Response::ok(self)
+        }
+        fn timeout(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            Response::spawn_many(self, vec![0, 3])
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn create_errors_and_grandchildren() {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Tree::Root).deadline(scope.now())
+        }).unwrap();
+        match lc.add_machine_with(|_| Response::error("failed".into())) {
+            Err(SpawnError::UserError(e)) => {
+                assert_eq!(e.to_string(), "failed")
+            }
+            _ => panic!("error is not propagated"),
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        assert_eq!(*log.borrow(), vec![
+            "error: rejected", "node 3", "spawned root",
+            "node 2", "spawned 3",
+            "leaf", "spawned 2"]);
+    }
+
+    enum Pool {
+        Parent(usize),
+        Worker(u32),
+    }
+
+    impl Machine for Pool {
+        type Context = Rc<RefCell<Vec<String>>>;
+        type Seed = u32;
+        fn create(seed: u32, scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            Response::ok(Pool::Worker(seed)).deadline(scope.now())
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            Response::ok(self)
+        }
+        fn child_exited(self, scope: &mut Scope<Self::Context>,
+                        _child: Token, reason: Exit)
+            -> Response<Self, u32>
+        {
+            scope.borrow_mut().push(format!("{}", reason));
+            match self {
+                Pool::Parent(1) => Response::done(),
+                Pool::Parent(x) => Response::ok(Pool::Parent(x - 1)),
+                Pool::Worker(_) => unreachable!(),
+            }
+        }
+        fn timeout(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            match self {
+                Pool::Parent(_) => Response::spawn_many(self, 0..3).linked(),
+                Pool::Worker(0) => Response::done(),
+                Pool::Worker(1) => Response::error("failed".into()),
+                Pool::Worker(_) => panic!("crashed"),
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, u32>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn linked_children() {
+        let mut cfg = Config::new();
+        cfg.catch_panics(true);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Pool::Parent(3)).deadline(scope.now())
+        }).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        // order of timeouts for children is undefined
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, vec!["done", "error: failed", "panic: crashed"]);
+    }
+
+    #[test]
+    fn default_child_exited() {
+        // Burst doesn't implement child_exited, the notification is ignored
+        let mut log = Rc::new(RefCell::new(Vec::new()));
+        let exit = Exit::Error("failed".into());
+        let machine = with_scope(Time::zero(), &mut log, |scope| {
+            Burst::Parent.child_exited(scope, Token(1), exit)
+                .expect_machine()
+        });
+        match machine {
+            Burst::Parent => {}
+            Burst::Child(_) => panic!("wrong machine"),
+        }
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn exit_handler() {
+        let mut cfg = Config::new();
+        cfg.catch_panics(true);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Pool::Parent(3)).deadline(scope.now())
+        }).unwrap();
+        lc.on_exit(|ctx: &mut Rc<RefCell<Vec<String>>>, _, name, reason| {
+            assert!(name.ends_with("Pool"));
+            ctx.borrow_mut().push(format!("exit {}", reason));
+        });
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, vec!["done", "error: failed",
+            "exit done", "exit done", "exit error: failed",
+            "exit panic: crashed", "panic: crashed"]);
+    }
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// Exits on timeout, with an error if the flag is set
+    struct Quit(bool);
+
+    impl Machine for Quit {
+        type Context = Log;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Log>) -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Log>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Log>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn timeout(self, _scope: &mut Scope<Log>) -> Response<Self, Void> {
+            if self.0 {
+                Response::error("failed".into())
+            } else {
+                Response::done()
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Log>) -> Response<Self, Void> {
+            unreachable!();
+        }
+    }
+
+    rotor_compose!{
+        enum Composed/ComposedSeed<Log> {
+            Single(Quit),
+            Pair(Compose2<Pool, Quit>),
+        }
+    }
+
+    #[test]
+    fn exit_handler_composed() {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Composed::Single(Quit(true))).deadline(scope.now())
+        }).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Composed::Pair(Compose2::B(Quit(false))))
+                .deadline(scope.now())
+        }).unwrap();
+        lc.on_exit(|ctx: &mut Log, _, name, reason| {
+            let name = name.rsplit("::").next().unwrap();
+            ctx.borrow_mut().push(format!("{} {}", name, reason));
+        });
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, vec!["Quit done", "Quit error: failed"]);
+    }
+
+    #[test]
+    fn trace_buffer() {
+        let mut cfg = Config::new();
+        cfg.trace_buffer(100);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Tree::Root).deadline(scope.now())
+        }).unwrap();
+        let inst = lc.instantiate(Rc::new(RefCell::new(Vec::new())));
+        let buffer = inst.trace_buffer().unwrap();
+        inst.run().unwrap();
+        let records = buffer.records().iter()
+            .map(|r| format!("{:?} {}", r.action, r.response))
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![
+            "Timeout spawn_many", "Create error", "SpawnError ok",
+            "Create spawn", "Spawned done",
+            "Create spawn", "Spawned done",
+            "Create done", "Spawned done"]);
+    }
+
+    #[test]
+    fn trace_uncaught_panic() {
+        let mut cfg = Config::new();
+        cfg.trace_buffer(100);
+        let mut lc = Loop::new(&cfg).unwrap();
+        lc.add_machine_with(|scope| {
+            Response::ok(Pool::Parent(3)).deadline(scope.now())
+        }).unwrap();
+        let inst = lc.instantiate(Rc::new(RefCell::new(Vec::new())));
+        let buffer = inst.trace_buffer().unwrap();
+        // The buffer is logged, and the panic is propagated
+        let payload = catch_unwind(AssertUnwindSafe(|| inst.run()))
+            .err().expect("panic is not propagated");
+        assert_eq!(panic_message(&payload), "crashed");
+        let records = buffer.records();
+        assert_eq!(format!("{:?} {}", records[0].action, records[0].response),
+                   "Timeout spawn_many");
+    }
+
+    enum Lister {
+        Idle(u32),
+        Lister,
+    }
+
+    impl Machine for Lister {
+        type Context = Rc<RefCell<Vec<String>>>;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn timeout(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            let me = format!("me {:?}", scope.token());
+            scope.borrow_mut().push(me);
+            for info in scope.machines() {
+                let line = format!("{:?} {}", info.token,
+                    info.description.unwrap());
+                scope.borrow_mut().push(line);
+            }
+            scope.shutdown_loop();
+            Response::done()
+        }
+        fn describe(&self) -> Option<String> {
+            match *self {
+                Lister::Idle(x) => Some(format!("idle {}", x)),
+                Lister::Lister => None,
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn introspection() {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|_| Response::ok(Lister::Idle(1))).unwrap();
+        lc.add_machine_with(|scope| {
+            assert_eq!(scope.token(), Token(1));
+            Response::ok(Lister::Lister).deadline(scope.now())
+        }).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut inst = lc.instantiate(log.clone());
+        inst.add_machine_with(|_| Response::ok(Lister::Idle(2))).unwrap();
+        let machines = inst.machines();
+        assert_eq!(machines.len(), 3);
+        assert_eq!(machines[1].description, None);
+        assert!(machines[1].deadline.is_some());
+        inst.run().unwrap();
+        assert_eq!(*log.borrow(), vec!["me Token(1)",
+            "Token(0) idle 1", "Token(2) idle 2"]);
+    }
+
+    struct Shared {
+        writer: PipeWriter,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    enum Stale {
+        Owner,
+        Driver(bool),
+        Reuser,
+    }
+
+    impl Machine for Stale {
+        type Context = Shared;
+        type Seed = ();
+        fn create(_seed: (), scope: &mut Scope<Shared>)
+            -> Response<Self, ()>
+        {
+            let line = format!("reused {:?}", scope.token());
+            scope.log.borrow_mut().push(line);
+            Response::ok(Stale::Reuser)
+        }
+        fn ready(self, _events: EventSet, scope: &mut Scope<Shared>)
+            -> Response<Self, ()>
+        {
+            scope.log.borrow_mut().push("phantom ready".to_string());
+            Response::ok(self)
+        }
+        fn spawned(self, scope: &mut Scope<Shared>) -> Response<Self, ()> {
+            // the socket registered by the owner becomes readable
+            scope.writer.write_all(b"x").unwrap();
+            let deadline = scope.now() + Duration::from_millis(50);
+            Response::ok(self).deadline(deadline)
+        }
+        fn timeout(self, scope: &mut Scope<Shared>) -> Response<Self, ()> {
+            match self {
+                Stale::Owner => Response::done(),
+                Stale::Driver(false) => {
+                    Response::spawn(Stale::Driver(true), ())
+                }
+                Stale::Driver(true) => {
+                    scope.shutdown_loop();
+                    Response::done()
+                }
+                Stale::Reuser => unreachable!(),
+            }
+        }
+        fn wakeup(self, _scope: &mut Scope<Shared>) -> Response<Self, ()> {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn stale_events() {
+        let (reader, writer) = pipe().unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = Config::new();
+        // so the token of the owner is reused
+        cfg.slab_capacity(2);
+        let lc = Loop::<Stale>::new(&cfg).unwrap();
+        let mut inst = lc.instantiate(Shared {
+            writer: writer,
+            log: log.clone(),
+        });
+        inst.add_machine_with(|scope| {
+            // The pipe is not owned by the machine, so it's left
+            // registered when the machine exits
+            scope.register(&reader, EventSet::readable(),
+                           PollOpt::edge()).unwrap();
+            Response::ok(Stale::Owner).deadline(scope.now())
+        }).unwrap();
+        inst.add_machine_with(|scope| {
+            // more than a timer tick after the owner exits
+            let deadline = scope.now() + Duration::from_millis(250);
+            Response::ok(Stale::Driver(false)).deadline(deadline)
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(*log.borrow(), vec!["reused Token(0)"]);
+    }
+
+    struct Leaked {
+        reader: Option<PipeReader>,
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    enum Leak {
+        Owner,
+        Waiter,
+    }
+
+    impl Machine for Leak {
+        type Context = Leaked;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Leaked>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, scope: &mut Scope<Leaked>)
+            -> Response<Self, Void>
+        {
+            match self {
+                Leak::Owner => scope.log.borrow_mut().push("owner ready"),
+                Leak::Waiter => scope.log.borrow_mut().push("waiter ready"),
+            }
+            Response::done()
+        }
+        fn spawned(self, _scope: &mut Scope<Leaked>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn timeout(self, scope: &mut Scope<Leaked>) -> Response<Self, Void> {
+            // Fails with EEXIST if the pipe is still registered
+            let reader = scope.reader.take().unwrap();
+            let result = scope.register(&reader, EventSet::readable(),
+                                        PollOpt::level());
+            scope.reader = Some(reader);
+            result.unwrap();
+            scope.log.borrow_mut().push("registered");
+            Response::ok(self)
+        }
+        fn wakeup(self, _scope: &mut Scope<Leaked>) -> Response<Self, Void> {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn deregister_on_exit() {
+        let (reader, mut writer) = pipe().unwrap();
+        // The pipe stays readable, so the level-triggered registration
+        // would wake up the loop over and over if left after the owner
+        writer.write_all(b"x").unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let lc = Loop::<Leak>::new(&Config::new()).unwrap();
+        let mut inst = lc.instantiate(Leaked {
+            reader: None,
+            log: log.clone(),
+        });
+        inst.add_machine_with(|scope| {
+            scope.register_tracked(&reader, EventSet::readable(),
+                                   PollOpt::level()).unwrap();
+            Response::ok(Leak::Owner)
+        }).unwrap();
+        inst.add_machine_with(|scope| {
+            scope.reader = Some(reader);
+            let deadline = scope.now() + Duration::from_millis(50);
+            Response::ok(Leak::Waiter).deadline(deadline)
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(*log.borrow(),
+                   vec!["owner ready", "registered", "waiter ready"]);
+    }
+
+    struct Sources {
+        _pipes: Vec<(PipeReader, PipeWriter)>,
+    }
+
+    impl Machine for Sources {
+        type Context = Rc<RefCell<Vec<String>>>;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn ready_source(self, events: EventSet, source: Source,
+                        scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            let line = format!("{:?} {}", source, events.is_readable());
+            scope.borrow_mut().push(line);
+            scope.shutdown_loop();
+            Response::done()
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn ready_source() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut inst = Loop::<Sources>::new(&Config::new()).unwrap()
+            .instantiate(log.clone());
+        inst.add_machine_with(|scope| {
+            let mut pipes = vec![pipe().unwrap(), pipe().unwrap()];
+            for (i, &(ref reader, _)) in pipes.iter().enumerate() {
+                scope.register_source(reader,
+                    Source(i as u8 + 1), EventSet::readable(),
+                    PollOpt::edge()).unwrap();
+            }
+            pipes[1].1.write_all(b"x").unwrap();
+            Response::ok(Sources { _pipes: pipes })
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(*log.borrow(), vec!["Source(2) true"]);
+    }
+
+    struct Woken;
+
+    impl Machine for Woken {
+        type Context = Rc<RefCell<u32>>;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            Response::done()
+        }
+        fn wakeup(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            *scope.borrow_mut() += 1;
+            Response::ok(self).deadline(scope.now() + Duration::from_millis(50))
+        }
+    }
+
+    #[test]
+    fn coalesce_wakeups() {
+        let counter = Rc::new(RefCell::new(0));
+        let lc = Loop::<Woken>::new(&Config::new()).unwrap();
+        let mut inst = lc.instantiate(counter.clone());
+        inst.add_machine_with(|scope| {
+            let notifier = scope.notifier();
+            for _ in 0..10000 {
+                notifier.wakeup().unwrap();
+            }
+            Response::ok(Woken)
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(*counter.borrow(), 1);
+    }
+
+    #[test]
+    fn wakeup_ack() {
+        let counter = Rc::new(RefCell::new(0));
+        let lc = Loop::<Woken>::new(&Config::new()).unwrap();
+        let mut inst = lc.instantiate(counter.clone());
+        let mut notifiers = Vec::new();
+        inst.add_machine_with(|scope| {
+            notifiers.push(scope.notifier());
+            Response::ok(Woken)
+        }).unwrap();
+        inst.add_machine_with(|scope| {
+            notifiers.push(scope.notifier());
+            Response::done()
+        }).unwrap();
+        // Reuses the token of the exited state machine, it's woken up
+        // but the acknowledgement is for the exited one
+        inst.add_machine_with(|_| Response::ok(Woken)).unwrap();
+        let vacant = notifiers.pop().unwrap();
+        let woken = notifiers.pop().unwrap();
+        let thread = thread::spawn(move || {
+            let first = vacant.wakeup_with_ack().unwrap().wait();
+            // The state machine exits on timeout after the wakeup, which
+            // stops the loop
+            let second = woken.wakeup_with_ack().unwrap().wait();
+            (first, second, woken)
+        });
+        inst.run().unwrap();
+        let (first, second, woken) = thread.join().unwrap();
+        assert_eq!(first, WakeupStatus::Vacant);
+        assert_eq!(second, WakeupStatus::Dispatched);
+        assert_eq!(*counter.borrow(), 2);
+        match woken.wakeup_with_ack() {
+            Err(WakeupError::Closed) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,50 @@
+use mio::Token;
+
+use {Time, Action};
+
+
+/// Information about a state machine in the loop
+///
+/// Returned by `Scope::machines` and `LoopInstance::machines`
+#[derive(Debug, Clone)]
+pub struct MachineInfo {
+    /// The token of the state machine
+    pub token: Token,
+    /// The deadline (i.e. the time of the next `timeout` action)
+    pub deadline: Option<Time>,
+    /// The result of `Machine::describe`
+    pub description: Option<String>,
+}
+
+/// Number of actions dispatched to the state machines of the loop
+///
+/// Returned by `Scope::counters` and `LoopInstance::counters`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub create: u64,
+    pub ready: u64,
+    pub spawned: u64,
+    pub spawn_error: u64,
+    pub child_exited: u64,
+    pub timeout: u64,
+    pub wakeup: u64,
+}
+
+/// A view of the state machines in the loop
+#[doc(hidden)]
+pub trait Introspect {
+    fn machines(&self) -> Vec<MachineInfo>;
+    fn counters(&self) -> Counters;
+}
+
+pub fn count(counters: &mut Counters, action: Action) {
+    match action {
+        Action::Create => counters.create += 1,
+        Action::Ready(_) => counters.ready += 1,
+        Action::Spawned => counters.spawned += 1,
+        Action::SpawnError => counters.spawn_error += 1,
+        Action::ChildExited(_) => counters.child_exited += 1,
+        Action::Timeout => counters.timeout += 1,
+        Action::Wakeup => counters.wakeup += 1,
+    }
+}
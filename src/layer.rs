@@ -0,0 +1,584 @@
+use std::any::Any;
+use std::cmp::min;
+use std::error::Error;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use mio::Token;
+use void::{Void, unreachable};
+
+use response::response_kind;
+use {Machine, Scope, Response, EventSet, SpawnError, Time, GenericScope};
+use Source;
+use Exit;
+
+
+/// The action that is dispatched to a state machine
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Create,
+    Ready(EventSet),
+    Spawned,
+    SpawnError,
+    ChildExited(Token),
+    Timeout,
+    Wakeup,
+}
+
+/// A reusable piece of behavior which may be attached to any state machine
+///
+/// Layers are used for cross-cutting concerns like logging or idle
+/// timeouts. Every action of the wrapped machine goes through the
+/// `Layer::call`, so the layer may do something before and after the action
+/// or inspect and replace the `Response`.
+///
+/// The layer is attached using `Layered` state machine. State machines
+/// spawned by the wrapped machine are wrapped too, using a layer returned
+/// by `Layer::child`.
+pub trait Layer<M: Machine>: Sized {
+
+    /// Creates a layer for the state machine spawned by this one
+    fn child(&self) -> Self;
+
+    /// Called when a layer is attached to the state machine
+    fn start(&mut self, _now: Time) {}
+
+    /// Wraps the call of the action handler of the inner state machine
+    ///
+    /// The `fun` must be called to actually execute the action. Default
+    /// implementation just calls it.
+    fn call<N, F>(&mut self, action: Action, scope: &mut Scope<M::Context>,
+        fun: F)
+        -> Response<M, N>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, N>
+    {
+        let _ = action;
+        fun(scope)
+    }
+
+    /// The deadline of the layer itself
+    ///
+    /// It's combined with the deadline of the state machine. When the
+    /// deadline passes the `expired` method is called (instead of the
+    /// `timeout` of the state machine)
+    fn deadline(&self) -> Option<Time> {
+        None
+    }
+
+    /// Called when the deadline of the layer passes
+    ///
+    /// Return error to stop the state machine. The layer should move its
+    /// deadline forward (or reset it), otherwise the deadline is ignored
+    /// until the layer changes it, rather than firing over and over.
+    fn expired(&mut self, _scope: &mut Scope<M::Context>)
+        -> Result<(), Box<Error>>
+    {
+        Ok(())
+    }
+}
+
+/// The state machine that wraps another one with a `Layer`
+///
+/// The state machine may be mounted into the loop as any other machine,
+/// including using it in `rotor_compose!`. Note that `Seed` of the layered
+/// machine contains the layer for the new machine.
+pub struct Layered<M, L> {
+    machine: M,
+    layer: L,
+    deadline: Option<Time>,
+    /// The layer's deadline which has already fired
+    expired: Option<Time>,
+}
+
+fn layered<M, L, N, T, F>(layer: L, response: Response<M, N>, seed: F)
+    -> Response<Layered<M, L>, T>
+    where M: Machine, L: Layer<M>, F: FnMut(N, &L) -> T
+{
+    layered_after(layer, response, seed, None)
+}
+
+/// Same as `layered` but ignores the layer's deadline if it's not later
+/// than `expired` (i.e. the layer didn't move it in `Layer::expired`)
+fn layered_after<M, L, N, T, F>(layer: L, response: Response<M, N>,
+    mut seed: F, expired: Option<Time>)
+    -> Response<Layered<M, L>, T>
+    where M: Machine, L: Layer<M>, F: FnMut(N, &L) -> T
+{
+    use response::ResponseImpl::*;
+    // Remembered until the layer moves its deadline, so it isn't put back
+    // by the following actions
+    let expired = match (layer.deadline(), expired) {
+        (Some(time), Some(now)) if time <= now => Some(now),
+        _ => None,
+    };
+    let wrap = |machine, deadline| {
+        Layered {
+            machine: machine,
+            layer: layer,
+            deadline: deadline,
+            expired: expired,
+        }
+    };
+    let layer_deadline = |layer: &L| {
+        match expired {
+            Some(_) => None,
+            None => layer.deadline(),
+        }
+    };
+    let imp = match response.0 {
+        Normal(m) => {
+            let me = wrap(m, None);
+            match layer_deadline(&me.layer) {
+                Some(time) => Deadline(me, time),
+                None => Normal(me),
+            }
+        }
+        Deadline(m, time) => {
+            let me = wrap(m, Some(time));
+            match layer_deadline(&me.layer) {
+                Some(ltime) => Deadline(me, min(time, ltime)),
+                None => Deadline(me, time),
+            }
+        }
+        Spawn(m, n, linked) => {
+            let me = wrap(m, None);
+            let n = seed(n, &me.layer);
+            Spawn(me, n, linked)
+        }
+        SpawnMany(m, seeds, linked) => {
+            let me = wrap(m, None);
+            let seeds = seeds.into_iter().map(|n| seed(n, &me.layer))
+                .collect();
+            SpawnMany(me, Box::new(seeds), linked)
+        }
+        Done => Done,
+        Error(e) => Error(e),
+    };
+    Response(imp)
+}
+
+fn child_seed<S, L: Layer<M>, M: Machine>(seed: S, layer: &L) -> (S, L) {
+    (seed, layer.child())
+}
+
+impl<M: Machine, L: Layer<M>> Layered<M, L> {
+    /// Attaches layer to the state machine
+    ///
+    /// Usually used to wrap the response of the state machine constructor:
+    ///
+    /// ```ignore
+    /// Layered::new(LogLayer::new("server"),
+    ///              Server::new(listener, scope), scope)
+    /// ```
+    pub fn new<S: GenericScope>(mut layer: L, response: Response<M, Void>,
+        scope: &S)
+        -> Response<Layered<M, L>, Void>
+    {
+        layer.start(scope.now());
+        layered(layer, response, |x, _| unreachable(x))
+    }
+    /// Returns a reference to the wrapped state machine
+    pub fn get_ref(&self) -> &M {
+        &self.machine
+    }
+    /// Returns a reference to the layer
+    pub fn layer(&self) -> &L {
+        &self.layer
+    }
+}
+
+impl<M: Machine, L: Layer<M>> Machine for Layered<M, L> {
+    type Context = M::Context;
+    type Seed = (M::Seed, L);
+
+    fn create((seed, mut layer): Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        layer.start(scope.now());
+        let resp = layer.call(Action::Create, scope,
+            |scope| M::create(seed, scope));
+        layered(layer, resp, child_seed)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::Ready(events), scope,
+            |scope| machine.ready(events, scope));
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn ready_source(self, events: EventSet, source: Source,
+                    scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::Ready(events), scope,
+            |scope| machine.ready_source(events, source, scope));
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::Spawned, scope,
+            |scope| machine.spawned(scope));
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::SpawnError, scope,
+            |scope| machine.spawn_error(scope, error.map(|(seed, _)| seed)));
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn child_exited(self, scope: &mut Scope<M::Context>,
+                    child: Token, reason: Exit)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::ChildExited(child), scope,
+            |scope| machine.child_exited(scope, child, reason));
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, deadline, mut expired } = self;
+        let now = scope.now();
+        if deadline.map(|x| x <= now).unwrap_or(false) {
+            let resp = layer.call(Action::Timeout, scope,
+                |scope| machine.timeout(scope));
+            return layered_after(layer, resp, child_seed, expired);
+        }
+        // The deadline which has already fired is not put into the loop,
+        // so the timeout is for the machine's deadline or spurious
+        if expired.is_none() &&
+            layer.deadline().map(|x| x <= now).unwrap_or(false)
+        {
+            if let Err(e) = layer.expired(scope) {
+                return Response::error(e);
+            }
+            expired = Some(now);
+        }
+        // Either the layer's deadline or a spurious timeout, keep the
+        // deadline of the state machine intact
+        let resp = match deadline {
+            Some(time) => Response::ok(machine).deadline(time),
+            None => Response::ok(machine),
+        };
+        layered_after(layer, resp, child_seed, expired)
+    }
+    fn describe(&self) -> Option<String> {
+        self.machine.describe()
+    }
+    fn name(&self) -> &'static str {
+        self.machine.name()
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let Layered { machine, mut layer, expired, .. } = self;
+        let resp = layer.call(Action::Wakeup, scope,
+            |scope| machine.wakeup(scope));
+        layered_after(layer, resp, child_seed, expired)
+    }
+}
+
+/// A layer that logs every action and its outcome at the debug level
+#[derive(Debug, Clone)]
+pub struct LogLayer {
+    name: &'static str,
+}
+
+impl LogLayer {
+    /// Create a logging layer, the `name` is prepended to every message
+    pub fn new(name: &'static str) -> LogLayer {
+        LogLayer { name: name }
+    }
+}
+
+impl<M: Machine> Layer<M> for LogLayer {
+    fn child(&self) -> LogLayer {
+        self.clone()
+    }
+    fn call<N, F>(&mut self, action: Action, scope: &mut Scope<M::Context>,
+        fun: F)
+        -> Response<M, N>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, N>
+    {
+        let resp = fun(scope);
+        match resp.cause() {
+            Some(e) => debug!("{}: {:?} -> error: {}", self.name, action, e),
+            None => debug!("{}: {:?} -> {}", self.name, action,
+                           response_kind(&resp)),
+        }
+        resp
+    }
+}
+
+/// A layer that stops the state machine if there was no I/O for a while
+///
+/// Only `ready` events reset the timer, i.e. wakeups and timeouts of the
+/// state machine are not considered an activity.
+#[derive(Debug, Clone)]
+pub struct IdleTimeout {
+    timeout: Duration,
+    deadline: Option<Time>,
+}
+
+impl IdleTimeout {
+    pub fn new(timeout: Duration) -> IdleTimeout {
+        IdleTimeout { timeout: timeout, deadline: None }
+    }
+}
+
+impl<M: Machine> Layer<M> for IdleTimeout {
+    fn child(&self) -> IdleTimeout {
+        IdleTimeout::new(self.timeout)
+    }
+    fn start(&mut self, now: Time) {
+        self.deadline = Some(now + self.timeout);
+    }
+    fn call<N, F>(&mut self, action: Action, scope: &mut Scope<M::Context>,
+        fun: F)
+        -> Response<M, N>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, N>
+    {
+        if let Action::Ready(_) = action {
+            self.deadline = Some(scope.now() + self.timeout);
+        }
+        fun(scope)
+    }
+    fn deadline(&self) -> Option<Time> {
+        self.deadline
+    }
+    fn expired(&mut self, _scope: &mut Scope<M::Context>)
+        -> Result<(), Box<Error>>
+    {
+        Err("idle timeout".into())
+    }
+}
+
+/// A layer that counts state machines stopped with `Response::error`
+///
+/// The counter is shared between all the state machines wrapped by this
+/// layer (including the spawned ones) and may be read from another thread.
+#[derive(Debug, Clone)]
+pub struct ErrorCounter {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Default for ErrorCounter {
+    fn default() -> ErrorCounter {
+        ErrorCounter::new()
+    }
+}
+
+impl ErrorCounter {
+    pub fn new() -> ErrorCounter {
+        ErrorCounter { counter: Arc::new(AtomicUsize::new(0)) }
+    }
+    /// Returns a shared counter, suitable for sending to another thread
+    pub fn counter(&self) -> Arc<AtomicUsize> {
+        self.counter.clone()
+    }
+    /// Returns the number of errors counted so far
+    pub fn get(&self) -> usize {
+        self.counter.load(Ordering::Relaxed)
+    }
+}
+
+impl<M: Machine> Layer<M> for ErrorCounter {
+    fn child(&self) -> ErrorCounter {
+        self.clone()
+    }
+    fn call<N, F>(&mut self, _action: Action, scope: &mut Scope<M::Context>,
+        fun: F)
+        -> Response<M, N>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, N>
+    {
+        let resp = fun(scope);
+        if resp.cause().is_some() {
+            self.counter.fetch_add(1, Ordering::Relaxed);
+        }
+        resp
+    }
+}
+
+/// A layer that turns a panic in the state machine into an error
+///
+/// The state machine is stopped with `Response::error` that contains the
+/// panic message. Note that panic may leave the context in inconsistent
+/// state, so it's not a replacement for proper error handling.
+#[derive(Debug, Clone)]
+pub struct CatchPanic;
+
+impl<M: Machine> Layer<M> for CatchPanic {
+    fn child(&self) -> CatchPanic {
+        CatchPanic
+    }
+    fn call<N, F>(&mut self, _action: Action, scope: &mut Scope<M::Context>,
+        fun: F)
+        -> Response<M, N>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, N>
+    {
+        match catch_unwind(AssertUnwindSafe(move || fun(scope))) {
+            Ok(resp) => resp,
+            Err(payload) => {
+                Response::error(format!("state machine panicked: {}",
+                    panic_message(&payload)).into())
+            }
+        }
+    }
+}
+
+/// Extracts the message from the payload of a panic
+pub fn panic_message(payload: &Box<Any + Send>) -> &str {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        &s[..]
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use mio::Token;
+    use void::Void;
+
+    use response::decompose;
+    use test_util::with_scope;
+    use {Machine, Scope, Response, EventSet, Time};
+    use super::{Layer, Layered, IdleTimeout, CatchPanic};
+    use super::{LogLayer, ErrorCounter};
+
+    struct Sleeper;
+
+    impl Machine for Sleeper {
+        type Context = ();
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<()>)
+            -> Response<Self, Void>
+        {
+            match seed {}
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+            -> Response<Self, Void>
+        {
+            Response::ok(self)
+        }
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            panic!("woken up");
+        }
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let start = Time::zero();
+        let idle = Duration::from_millis(100);
+        let (m, _, deadline) = with_scope(start, &mut (), |scope| {
+            decompose(Token(0), Layered::new(IdleTimeout::new(idle),
+                                             Response::ok(Sleeper), scope))
+        });
+        assert_eq!(deadline, Some(start + idle));
+        let active = start + Duration::from_millis(50);
+        let (m, _, deadline) = with_scope(active, &mut (), |scope| {
+            decompose(Token(0),
+                m.unwrap().ready(EventSet::readable(), scope))
+        });
+        assert_eq!(deadline, Some(active + idle));
+        // deadline was moved forward, so this timeout is spurious
+        let (m, _, deadline) = with_scope(start + idle, &mut (), |scope| {
+            decompose(Token(0), m.unwrap().timeout(scope))
+        });
+        assert_eq!(deadline, Some(active + idle));
+        let (m, _, _) = with_scope(active + idle, &mut (), |scope| {
+            decompose(Token(0), m.unwrap().timeout(scope))
+        });
+        assert_eq!(m.err().unwrap().unwrap().to_string(), "idle timeout");
+    }
+
+    #[test]
+    fn catch_panic() {
+        let (m, _, _) = with_scope(Time::zero(), &mut (), |scope| {
+            let m = Layered::new(CatchPanic, Response::ok(Sleeper), scope);
+            let (m, _, _) = decompose(Token(0), m);
+            decompose(Token(0), m.unwrap().wakeup(scope))
+        });
+        assert_eq!(m.err().unwrap().unwrap().to_string(),
+                   "state machine panicked: woken up");
+    }
+
+    /// A layer with a fixed deadline and the default `expired`
+    struct Fixed(Time);
+
+    impl<M: Machine> Layer<M> for Fixed {
+        fn child(&self) -> Fixed {
+            Fixed(self.0)
+        }
+        fn deadline(&self) -> Option<Time> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn expired_deadline_is_not_rearmed() {
+        let at = Time::zero() + Duration::from_millis(100);
+        let (m, _, deadline) = with_scope(Time::zero(), &mut (), |scope| {
+            decompose(Token(0),
+                Layered::new(Fixed(at), Response::ok(Sleeper), scope))
+        });
+        assert_eq!(deadline, Some(at));
+        let (m, _, deadline) = with_scope(at, &mut (), |scope| {
+            decompose(Token(0), m.unwrap().timeout(scope))
+        });
+        assert!(m.is_ok());
+        assert_eq!(deadline, None);
+        // the following actions don't put it back either
+        let later = at + Duration::from_millis(1);
+        let (m, _, deadline) = with_scope(later, &mut (), |scope| {
+            decompose(Token(0), m.unwrap().ready(EventSet::readable(), scope))
+        });
+        assert!(m.is_ok());
+        assert_eq!(deadline, None);
+        let later = at + Duration::from_millis(2);
+        let (m, _, deadline) = with_scope(later, &mut (), |scope| {
+            decompose(Token(0), m.unwrap().timeout(scope))
+        });
+        assert!(m.is_ok());
+        assert_eq!(deadline, None);
+    }
+
+    #[test]
+    fn count_errors() {
+        let errors = ErrorCounter::new();
+        let (m, _, _) = with_scope(Time::zero(), &mut (), |scope| {
+            let m = Layered::new(CatchPanic, Response::ok(Sleeper), scope);
+            let m = Layered::new(errors.clone(), m, scope);
+            let m = Layered::new(LogLayer::new("test"), m, scope);
+            let (m, _, _) = decompose(Token(0), m);
+            let (m, _, _) = decompose(Token(0),
+                m.unwrap().ready(EventSet::readable(), scope));
+            assert_eq!(errors.get(), 0);
+            decompose(Token(0), m.unwrap().wakeup(scope))
+        });
+        assert!(m.is_err());
+        assert_eq!(errors.get(), 1);
+        assert_eq!(errors.counter().load(Ordering::Relaxed), 1);
+    }
+}
@@ -9,32 +9,66 @@ pub extern crate mio as mio_original;
 pub extern crate slab;
 #[macro_use] extern crate log;
 #[macro_use] extern crate quick_error;
+#[cfg(target_os = "linux")] extern crate libc;
 
+#[macro_use] mod macros;
 mod handler;
 mod scope;
 mod loop_api;
 mod response;
 mod compose;
-mod macros;
 mod machine;
 mod notify;
 mod config;
 mod creator;
 mod error;
+mod exit;
 mod loop_time;
+mod mount;
+mod layer;
+mod supervisor;
+mod trace;
+mod introspect;
+mod readiness;
+mod tokens;
+mod registry;
+mod proxy;
+mod rate;
+mod timer;
+mod wall_clock;
+mod cron;
+mod ticker;
+#[cfg(unix)] mod control;
+#[cfg(test)] mod test_util;
 
 pub use machine::Machine;
-pub use scope::{Scope, EarlyScope, GenericScope};
+pub use scope::{Scope, EarlyScope, GenericScope, GenericScopeExt};
 pub use scope::{scope as _scope, early_scope as _early_scope};
-pub use notify::{Notifier, WakeupError};
-pub use config::Config;
+pub use notify::{Notifier, WakeupError, WakeupOverflow};
+pub use notify::{WakeupAck, WakeupStatus};
+pub use config::{Config, ConfigError, LoopError};
 pub use creator::{LoopCreator as Loop, LoopInstance};
 pub use error::SpawnError;
+pub use exit::Exit;
 pub use loop_time::Time;
 pub use handler::{Timeo as _Timeo, Notify as _Notify};
 pub use loop_api::{LoopApi as _LoopApi};
 
 pub use compose::{Compose2};
+pub use mount::{Mount, SubContext};
+pub use layer::{Layer, Layered, Action};
+pub use layer::{LogLayer, IdleTimeout, ErrorCounter, CatchPanic};
+pub use supervisor::{Supervisor, Strategy};
+pub use trace::{TraceRecord, TraceBuffer};
+pub use introspect::{MachineInfo, Counters};
+pub use readiness::Tracked;
+pub use tokens::Source;
+pub use proxy::{Proxy, Stream as ProxyStream};
+pub use rate::{TokenBucket, LeakyBucket};
+pub use wall_clock::{WallDeadline, WallTimer};
+pub use cron::{Cron, CronError};
+pub use ticker::{Ticker, MissedTicks, Periodic};
+#[cfg(unix)] pub use control::Control;
 
 // Re-export mio types used in rotor
 pub use mio::{Ready as EventSet, Evented, PollOpt};
@@ -1,11 +1,14 @@
 use std::io;
 use std::time::Duration;
+#[cfg(unix)] use std::os::unix::io::RawFd;
 
 use mio::Token;
 use mio::deprecated::EventLoop;
 
 use handler::{Handler, Timeo};
-use {Machine};
+use registry::Registry;
+use timer::{Timers, TimerHandle};
+use {Machine, Time};
 use {Evented, EventSet, PollOpt, Timeout, TimerError};
 
 
@@ -20,6 +23,61 @@ pub trait LoopApi {
         -> Result<Timeout, TimerError>;
     fn clear_timeout(&mut self, token: Timeout) -> bool;
     fn shutdown(&mut self);
+    /// Sets the deadline of the state machine
+    ///
+    /// Only the loop itself is able to serve deadlines with the timerfd in
+    /// the `precise_time` mode, other implementations fall back to the
+    /// mio's timer
+    #[cfg(not(feature="precise_time"))]
+    fn set_deadline(&mut self, token: Token, now: Time, deadline: Time)
+        -> Result<TimerHandle, TimerError>
+    {
+        self.timeout_ms(token, ::loop_time::mio_timeout_ms(now, deadline))
+    }
+    #[cfg(feature="precise_time")]
+    fn set_deadline(&mut self, token: Token, now: Time, deadline: Time)
+        -> Result<TimerHandle, TimerError>
+    {
+        self.timeout_ms(token, ::loop_time::mio_timeout_ms(now, deadline))
+            .map(TimerHandle::Mio)
+    }
+    /// Clears the deadline set by `set_deadline`
+    #[cfg(not(feature="precise_time"))]
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool {
+        self.clear_timeout(handle)
+    }
+    #[cfg(feature="precise_time")]
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool {
+        match handle {
+            TimerHandle::Mio(timeout) => self.clear_timeout(timeout),
+            TimerHandle::Deadline(_) => false,
+        }
+    }
+    /// Records that the state machine has registered the file descriptor
+    #[cfg(unix)]
+    fn track(&mut self, _token: Token, _fd: RawFd) {}
+    /// Forgets the file descriptor which is deregistered explicitly
+    #[cfg(unix)]
+    fn untrack(&mut self, _fd: RawFd) {}
+    /// Deregisters the file descriptors left by the state machine
+    fn release(&mut self, _token: Token) {}
+}
+
+/// The event loop along with the timers of the state machine deadlines
+/// and the registry of the I/O objects
+pub struct Api<'a, M: Machine + 'a> {
+    pub eloop: &'a mut EventLoop<Handler<M>>,
+    pub timers: &'a mut Timers,
+    pub registry: &'a mut Registry,
+}
+
+impl<'a, M: Machine> Api<'a, M> {
+    pub fn new(eloop: &'a mut EventLoop<Handler<M>>, timers: &'a mut Timers,
+        registry: &'a mut Registry)
+        -> Api<'a, M>
+    {
+        Api { eloop: eloop, timers: timers, registry: registry }
+    }
 }
 
 impl<'a, M: Machine> LoopApi for EventLoop<Handler<M>>
@@ -54,3 +112,55 @@ impl<'a, M: Machine> LoopApi for EventLoop<Handler<M>>
         self.shutdown()
     }
 }
+
+impl<'a, M: Machine> LoopApi for Api<'a, M>
+{
+    fn register(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.eloop.register(io, token, interest, opt)
+    }
+
+    fn reregister(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.eloop.reregister(io, token, interest, opt)
+    }
+
+    fn deregister(&mut self, io: &Evented) -> io::Result<()>
+    {
+        self.eloop.deregister(io)
+    }
+
+    fn timeout_ms(&mut self, token: Token, delay: u64)
+        -> Result<Timeout, TimerError>
+    {
+        self.eloop.timeout(Timeo::Fsm(token), Duration::from_millis(delay))
+    }
+    fn clear_timeout(&mut self, token: Timeout) -> bool
+    {
+        self.eloop.clear_timeout(&token)
+    }
+    fn shutdown(&mut self) {
+        self.eloop.shutdown()
+    }
+    fn set_deadline(&mut self, token: Token, now: Time, deadline: Time)
+        -> Result<TimerHandle, TimerError>
+    {
+        self.timers.set(self.eloop, token, now, deadline)
+    }
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool {
+        self.timers.clear(self.eloop, handle)
+    }
+    #[cfg(unix)]
+    fn track(&mut self, token: Token, fd: RawFd) {
+        self.registry.add(token, fd)
+    }
+    #[cfg(unix)]
+    fn untrack(&mut self, fd: RawFd) {
+        self.registry.remove(fd)
+    }
+    fn release(&mut self, token: Token) {
+        self.registry.release(self.eloop, token)
+    }
+}
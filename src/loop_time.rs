@@ -1,13 +1,16 @@
-
-use std::ops::Add;
+use std::fmt;
+use std::ops::{Add, Sub, AddAssign, SubAssign};
 use std::time::{Duration, Instant, SystemTime};
 
+use GenericScope;
+
 /// The current time
 ///
 /// This value is similar to (and directly derived from) the
 /// `time::SteadyTime`.  But it has three important properties:
 ///
-/// 1. It has a millisecond precision
+/// 1. It has a millisecond precision (microsecond with `precise_time`
+///    feature, see below)
 /// 2. It's size is 8 bytes (SteadyTime is 16 bytes)
 /// 3. It supports math with `std::time::Duration` (more future-proof)
 ///
@@ -21,18 +24,93 @@ use std::time::{Duration, Instant, SystemTime};
 /// we truncate (i.e. floor) the duration value. We may change this in future.
 /// Note that for timeouts this works well enough, as mio already bumps the
 /// timeout to at least a millisecond ahead.
+///
+/// # High Precision Mode
+///
+/// When the crate is built with the `precise_time` feature (Linux only),
+/// the time is counted in microseconds and deadlines of state machines are
+/// served by a `timerfd` instead of the mio's timer wheel. So deadlines
+/// are precise to tens of microseconds instead of being rounded up to the
+/// next timer tick. The API is the same, use `as_millis()`/`as_micros()`
+/// instead of relying on the unit of the internal counter.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Time(u64);
 
 
-fn millis(dur: Duration) -> u64 {
-    dur.as_secs()*1000 + (dur.subsec_nanos()/1000000) as u64
+/// Number of units of `Time` in a millisecond
+#[cfg(not(feature="precise_time"))]
+const UNITS_PER_MS: u64 = 1;
+#[cfg(feature="precise_time")]
+const UNITS_PER_MS: u64 = 1000;
+
+const NANOS_PER_UNIT: u32 = 1000000 / UNITS_PER_MS as u32;
+
+fn units(dur: Duration) -> u64 {
+    dur.as_secs()*1000*UNITS_PER_MS
+        + (dur.subsec_nanos()/NANOS_PER_UNIT) as u64
+}
+
+fn checked_units(dur: Duration) -> Option<u64> {
+    dur.as_secs().checked_mul(1000*UNITS_PER_MS)
+        .and_then(|x| x.checked_add((dur.subsec_nanos()/NANOS_PER_UNIT) as u64))
+}
+
+fn duration(units: u64) -> Duration {
+    let per_sec = 1000*UNITS_PER_MS;
+    Duration::new(units / per_sec,
+                  (units % per_sec) as u32 * NANOS_PER_UNIT)
 }
 
 impl Add<Duration> for Time {
     type Output = Time;
     fn add(self, rhs: Duration) -> Time {
-        Time(self.0 + millis(rhs))
+        self.checked_add(rhs).expect("overflow when adding duration to time")
+    }
+}
+
+impl AddAssign<Duration> for Time {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Time {
+    type Output = Time;
+    /// Subtracts the duration
+    ///
+    /// # Panics
+    ///
+    /// When the result is earlier than the start of the loop
+    fn sub(self, rhs: Duration) -> Time {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from time")
+    }
+}
+
+impl SubAssign<Duration> for Time {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Time> for Time {
+    type Output = Duration;
+    /// Returns the duration between two times, see `duration_since`
+    fn sub(self, rhs: Time) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Display for Time {
+    /// Formats the time since the start of the loop, e.g. `12.345s`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if UNITS_PER_MS == 1 {
+            let ms = self.as_millis();
+            write!(f, "{}.{:03}s", ms / 1000, ms % 1000)
+        } else {
+            let us = self.as_micros();
+            write!(f, "{}.{:06}s", us / 1000000, us % 1000000)
+        }
     }
 }
 
@@ -44,10 +122,59 @@ impl Time {
         // implement NonZero in the future
         Time(1)
     }
+    /// Milliseconds since the start of the loop
+    pub fn as_millis(&self) -> u64 {
+        (self.0 - 1) / UNITS_PER_MS
+    }
+    /// Microseconds since the start of the loop
+    ///
+    /// Always a multiple of a thousand unless `precise_time` feature is
+    /// enabled
+    pub fn as_micros(&self) -> u64 {
+        (self.0 - 1) * (1000 / UNITS_PER_MS)
+    }
+    /// Returns the duration elapsed from the `earlier` time to this one
+    ///
+    /// Returns zero duration if `earlier` is actually later than this time
+    pub fn duration_since(&self, earlier: Time) -> Duration {
+        self.checked_duration_since(earlier)
+            .unwrap_or(Duration::from_millis(0))
+    }
+    /// Returns the duration elapsed from the `earlier` time to this one,
+    /// or `None` if `earlier` is later than this time
+    pub fn checked_duration_since(&self, earlier: Time) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(duration)
+    }
+    /// Returns the duration elapsed since this time
+    ///
+    /// Zero if the time is in the future.
+    pub fn elapsed<S: GenericScope>(&self, scope: &S) -> Duration {
+        scope.now().duration_since(*self)
+    }
+    /// Adds the duration, returns `None` on overflow
+    pub fn checked_add(&self, duration: Duration) -> Option<Time> {
+        checked_units(duration).and_then(|x| self.0.checked_add(x))
+            .map(Time)
+    }
+    /// Subtracts the duration, returns `None` if the result is earlier
+    /// than the start of the loop
+    pub fn checked_sub(&self, duration: Duration) -> Option<Time> {
+        checked_units(duration).and_then(|x| self.0.checked_sub(x))
+            .and_then(|x| if x >= 1 { Some(Time(x)) } else { None })
+    }
+    /// Adds the duration, the result is clamped to the maximum time
+    pub fn saturating_add(&self, duration: Duration) -> Time {
+        self.checked_add(duration).unwrap_or(Time(u64::max_value()))
+    }
+    /// Subtracts the duration, the result is clamped to the start of the
+    /// loop (i.e. `Time::zero()`)
+    pub fn saturating_sub(&self, duration: Duration) -> Time {
+        self.checked_sub(duration).unwrap_or(Time::zero())
+    }
 }
 
 pub fn make_time(base: Instant, now: Instant) -> Time {
-    Time(millis(now.duration_since(base))
+    Time(units(now.duration_since(base))
          // Time starts with 1 not with zero
          + 1)
 }
@@ -58,24 +185,52 @@ pub fn mio_timeout_ms(now: Time, event: Time) -> u64 {
         // millisecond precision, while mio calculates at the nanosecond
         // precision (but doesn't expose it). So wake up time may be up
         // to a millisecond smaller then expected
-        event.0 - now.0 + 1
+        (event.0 - now.0 + UNITS_PER_MS - 1) / UNITS_PER_MS + 1
     } else {
         0
     }
 }
 
+/// Number of milliseconds from `from` to `to`, negative if `to` is earlier
+pub fn millis_between(from: Time, to: Time) -> i64 {
+    (to.0 as i64 - from.0 as i64) / UNITS_PER_MS as i64
+}
+
+/// Number of microseconds from `now` to `event`, zero if it's in the past
+#[cfg(feature="precise_time")]
+pub fn micros_until(now: Time, event: Time) -> u64 {
+    event.0.saturating_sub(now.0) * (1000 / UNITS_PER_MS)
+}
+
 pub fn estimate_system_time(now: Time, value: Time) -> SystemTime {
-    SystemTime::now() + Duration::from_millis(value.0 - now.0)
+    if value >= now {
+        SystemTime::now() + (value - now)
+    } else {
+        SystemTime::now() - (now - value)
+    }
+}
+
+/// The reverse of `estimate_system_time`
+///
+/// System time earlier than the start of the loop is clamped to it
+pub fn estimate_time(now: Time, value: SystemTime) -> Time {
+    match value.duration_since(SystemTime::now()) {
+        Ok(ahead) => now.saturating_add(ahead),
+        Err(e) => now.saturating_sub(e.duration()),
+    }
 }
 
 
 #[cfg(test)]
 mod test {
-    use super::Time;
-    use std::time::Duration;
+    use std::mem::size_of;
+    use std::time::{Duration, SystemTime};
+
+    use super::{Time, estimate_system_time, estimate_time};
 
 
     #[test]
+    #[cfg(not(feature="precise_time"))]
     fn test_add_duration() {
         let tm = Time::zero();
         assert_eq!(tm + Duration::new(10, 0), Time(10001));
@@ -85,4 +240,49 @@ mod test {
                    Time(5021));
     }
 
+    #[test]
+    fn test_arithmetic() {
+        let tm = Time::zero() + Duration::from_millis(1500);
+        assert_eq!((tm - Duration::from_millis(500)).as_millis(), 1000);
+        assert_eq!(tm - Time::zero(), Duration::from_millis(1500));
+        assert_eq!(Time::zero() - tm, Duration::from_millis(0));
+        assert_eq!(Time::zero().checked_duration_since(tm), None);
+        assert_eq!(tm.checked_sub(Duration::from_secs(2)), None);
+        assert_eq!(tm.saturating_sub(Duration::from_secs(2)), Time::zero());
+        assert_eq!(tm.checked_add(Duration::from_secs(u64::max_value())),
+                   None);
+        assert_eq!(tm.saturating_add(Duration::from_secs(u64::max_value())),
+                   Time(u64::max_value()));
+        let mut tm2 = tm;
+        tm2 += Duration::from_millis(10);
+        tm2 -= Duration::from_millis(20);
+        assert_eq!(tm2.as_millis(), 1490);
+        assert_eq!(tm2.as_micros(), 1490000);
+        #[cfg(not(feature="precise_time"))]
+        assert_eq!(tm2.to_string(), "1.490s");
+        #[cfg(feature="precise_time")]
+        assert_eq!(tm2.to_string(), "1.490000s");
+        assert_eq!(size_of::<Time>(), 8);
+    }
+
+    #[test]
+    fn test_system_time_in_the_past() {
+        let now = Time::zero() + Duration::from_secs(10);
+        let past = estimate_system_time(now, Time::zero());
+        let future = estimate_system_time(now, now + Duration::from_secs(5));
+        assert!(past < SystemTime::now());
+        assert!(future > SystemTime::now());
+    }
+
+    #[test]
+    fn test_estimate_time() {
+        let now = Time::zero() + Duration::from_secs(10);
+        let sys = SystemTime::now();
+        let future = estimate_time(now, sys + Duration::from_secs(5));
+        assert!(future > now + Duration::from_millis(4900));
+        assert!(future <= now + Duration::from_secs(5));
+        assert_eq!(estimate_time(now, sys - Duration::from_secs(60)),
+                   Time::zero());
+    }
+
 }
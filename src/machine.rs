@@ -1,6 +1,8 @@
-use void::Void;
+use std::any::type_name;
 
-use {Response, Scope, EventSet, SpawnError};
+use mio::Token;
+
+use {Response, Scope, EventSet, SpawnError, Exit, Source};
 
 
 /// A trait that every state machine in the loop must implement
@@ -25,19 +27,38 @@ pub trait Machine: Sized {
     /// is negligible. Most errors here should be resource exhaustion, like
     /// there are no slots in Slab or system limit on epoll watches exceeded.
     ///
+    /// If `Response::error(..)` is returned, the seed is rejected and
+    /// the error is passed to `Machine::spawn_error` of the parent state
+    /// machine as `SpawnError::UserError`. If `Response::done()` is returned,
+    /// no state machine is created, but this is not considered an error.
+    ///
+    /// The newly created machine may also `Response::spawn(..)` its own
+    /// child. In this case the `spawned` (or `spawn_error`) action is
+    /// called on the new machine after it's put into the loop.
+    ///
     /// Note: this method is used internally (by event loop) to create a
     /// socket from a Seed returned by this machine. This method should
     /// **not** be used to create machine by external code. Create a
     /// machine-specific `Type::new` method for the purpose.
-    ///
-    /// Note: we don't support spawning more state machines in create handler
     fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>)
-        -> Response<Self, Void>;
+        -> Response<Self, Self::Seed>;
 
     /// Socket readiness notification
     fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>;
 
+    /// Readiness notification of the I/O object registered as `source`
+    ///
+    /// This is what the loop actually calls on readiness, the default
+    /// implementation calls `ready`. Override it if the state machine has
+    /// several I/O objects registered with `Scope::register_source`.
+    fn ready_source(self, events: EventSet, _source: Source,
+                    scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        self.ready(events, scope)
+    }
+
     /// Called after spawn event
     ///
     /// This is mostly a continuation event. I.e. when you accept a socket
@@ -61,10 +82,43 @@ pub trait Machine: Sized {
         panic!("Error spawning state machine: {}", error);
     }
 
+    /// A linked child state machine has exited
+    ///
+    /// Called only for children spawned with `Response::linked()`. The
+    /// `child` is the token of the exited state machine. Note the token
+    /// may be reused for another state machine right away.
+    ///
+    /// The default implementation ignores the notification.
+    fn child_exited(self, _scope: &mut Scope<Self::Context>,
+                    _child: Token, _reason: Exit)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(self)
+    }
+
     /// Timeout happened
     fn timeout(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>;
 
+    /// Returns a human-readable description of the state machine
+    ///
+    /// It's used for introspection (see `Scope::machines` and
+    /// `LoopInstance::machines`), e.g. to list connections in an admin
+    /// interface. Returns `None` by default.
+    fn describe(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the name of the state machine
+    ///
+    /// It's passed to the exit handler (see `LoopCreator::on_exit`).
+    /// Returns the type name by default. Composed state machines return
+    /// the name of the wrapped state machine, so the handler knows which
+    /// one has exited.
+    fn name(&self) -> &'static str {
+        type_name::<Self>()
+    }
+
     /// Message received
     ///
     /// Note the spurious wakeups are possible, because messages are
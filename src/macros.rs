@@ -19,6 +19,9 @@
 /// This creates a an `Fsm` state machine type which is enum with two options.
 /// And `Seed` state machine type, which is also enum with same option names
 /// but uses `<HttpMachine as rotor::Machine>::Seed` for the wrapped type.
+///
+/// If you need generic children or custom handling of some actions, use
+/// `#[derive(Machine)]` from the `rotor-derive` crate instead.
 #[macro_export]
 macro_rules! rotor_compose {
     /* TODO(tailhook) make and check generic combinators
@@ -59,13 +62,12 @@ macro_rules! rotor_compose {
             type Context = $ctx_typ;
             type Seed = $cname;
             fn create(seed: $cname, scope: &mut $crate::Scope<$ctx_typ>)
-                -> $crate::Response<Self, $crate::Void>
+                -> $crate::Response<Self, Self::Seed>
             {
                 match seed {
                     $( $cname::$iname (x)
                         => $crate::Machine::create(x, scope)
-                            .map($name::$iname,
-                                 |x| $crate::void::unreachable(x)),
+                            .map($name::$iname, $cname::$iname),
                     )*
                 }
             }
@@ -82,6 +84,20 @@ macro_rules! rotor_compose {
                     )*
                 }
             }
+            fn ready_source(self, events: $crate::EventSet,
+                            source: $crate::Source,
+                            scope: &mut $crate::Scope<$ctx_typ>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.ready_source(events, source, scope)
+                                .map($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
             fn spawned(self, scope: &mut $crate::Scope<$ctx_typ>)
                 -> $crate::Response<Self, Self::Seed>
             {
@@ -94,6 +110,37 @@ macro_rules! rotor_compose {
                     )*
                 }
             }
+            fn spawn_error(self, scope: &mut $crate::Scope<$ctx_typ>,
+                           error: $crate::SpawnError<Self::Seed>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.spawn_error(scope, error.map(|seed| match seed {
+                                $cname::$iname(s) => s,
+                                #[allow(unreachable_patterns)]
+                                _ => unreachable!("Seed returned by {}::{} \
+                                    is of different type",
+                                    stringify!($name), stringify!($iname)),
+                            })).map($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
+            fn child_exited(self, scope: &mut $crate::Scope<$ctx_typ>,
+                            child: $crate::mio::Token, reason: $crate::Exit)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.child_exited(scope, child, reason)
+                                .map($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
             fn timeout(self, scope: &mut $crate::Scope<$ctx_typ>)
                 -> $crate::Response<Self, Self::Seed>
             {
@@ -106,6 +153,20 @@ macro_rules! rotor_compose {
                     )*
                 }
             }
+            fn describe(&self) -> Option<String> {
+                match *self {
+                    $(
+                        $name::$iname(ref m) => m.describe(),
+                    )*
+                }
+            }
+            fn name(&self) -> &'static str {
+                match *self {
+                    $(
+                        $name::$iname(ref m) => m.name(),
+                    )*
+                }
+            }
             fn wakeup(self, scope: &mut $crate::Scope<$ctx_typ>)
                 -> $crate::Response<Self, Self::Seed>
             {
@@ -0,0 +1,204 @@
+use std::marker::PhantomData;
+
+use mio::Token;
+
+use {Machine, Scope, Response, EventSet, SpawnError, Exit, Source};
+
+
+/// A context which contains the context of some sub-application
+///
+/// Implement this for your application's context to be able to `Mount`
+/// a state machine, which has its own context type, into the main loop.
+pub trait SubContext<T> {
+    fn sub_context(&mut self) -> &mut T;
+}
+
+/// Adapts state machine to a bigger context
+///
+/// Every action of the wrapped machine is called with a scope projected
+/// to the `M::Context` using the `SubContext` trait of the context `C`. This
+/// allows to mount a library state machine (e.g. HTTP server) which expects
+/// its own context type, into the loop of a bigger application without
+/// newtype wrappers:
+///
+/// ```ignore
+/// struct Context {
+///     http: HttpContext,
+///     // ...
+/// }
+///
+/// impl SubContext<HttpContext> for Context {
+///     fn sub_context(&mut self) -> &mut HttpContext { &mut self.http }
+/// }
+///
+/// rotor_compose!{
+///     pub enum Fsm/Seed<Context> {
+///         Http(Mount<HttpMachine, Context>),
+///         Dns(DnsMachine),
+///     }
+/// }
+/// ```
+pub struct Mount<M, C> {
+    machine: M,
+    phantom: PhantomData<*const C>,
+}
+
+impl<M, C> Mount<M, C> {
+    /// Wraps a machine
+    ///
+    /// Usually this is used as a mapper for a response of a constructor:
+    /// `HttpMachine::new(..).wrap(Mount::new)`
+    pub fn new(machine: M) -> Mount<M, C> {
+        Mount {
+            machine: machine,
+            phantom: PhantomData,
+        }
+    }
+    /// Returns a reference to the wrapped state machine
+    pub fn get_ref(&self) -> &M {
+        &self.machine
+    }
+    /// Unwraps the state machine
+    pub fn into_inner(self) -> M {
+        self.machine
+    }
+}
+
+impl<M, C> Machine for Mount<M, C>
+    where M: Machine, C: SubContext<M::Context>
+{
+    type Context = C;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, &mut scope.project(C::sub_context)).wrap(Mount::new)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.machine.ready(events, &mut scope.project(C::sub_context))
+            .wrap(Mount::new)
+    }
+    fn ready_source(self, events: EventSet, source: Source,
+                    scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.machine.ready_source(events, source,
+                                  &mut scope.project(C::sub_context))
+            .wrap(Mount::new)
+    }
+    fn spawned(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.machine.spawned(&mut scope.project(C::sub_context))
+            .wrap(Mount::new)
+    }
+    fn spawn_error(self, scope: &mut Scope<C>, error: SpawnError<M::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        self.machine.spawn_error(&mut scope.project(C::sub_context), error)
+            .wrap(Mount::new)
+    }
+    fn child_exited(self, scope: &mut Scope<C>, child: Token, reason: Exit)
+        -> Response<Self, Self::Seed>
+    {
+        self.machine.child_exited(&mut scope.project(C::sub_context),
+                                  child, reason)
+            .wrap(Mount::new)
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.machine.timeout(&mut scope.project(C::sub_context))
+            .wrap(Mount::new)
+    }
+    fn describe(&self) -> Option<String> {
+        self.machine.describe()
+    }
+    fn name(&self) -> &'static str {
+        self.machine.name()
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.machine.wakeup(&mut scope.project(C::sub_context))
+            .wrap(Mount::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use void::{Void, unreachable};
+
+    use {Machine, Scope, Response, EventSet, Loop, Config};
+    use super::{Mount, SubContext};
+
+    struct Sub {
+        hits: u32,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    struct App {
+        name: &'static str,
+        sub: Sub,
+    }
+
+    impl SubContext<Sub> for App {
+        fn sub_context(&mut self) -> &mut Sub { &mut self.sub }
+    }
+
+    /// A machine which knows nothing about `App`
+    struct Inner;
+
+    impl Inner {
+        fn hit(scope: &mut Scope<Sub>, action: &str) {
+            scope.hits += 1;
+            let msg = format!("{} {}", action, scope.hits);
+            scope.log.borrow_mut().push(msg);
+        }
+    }
+
+    impl Machine for Inner {
+        type Context = Sub;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Sub>) -> Response<Self, Void>
+        {
+            unreachable(seed)
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Sub>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Sub>) -> Response<Self, Void> {
+            unreachable!();
+        }
+        fn timeout(self, scope: &mut Scope<Sub>) -> Response<Self, Void> {
+            Inner::hit(scope, "timeout");
+            scope.notifier().wakeup().unwrap();
+            Response::ok(self)
+        }
+        fn wakeup(self, scope: &mut Scope<Sub>) -> Response<Self, Void> {
+            Inner::hit(scope, "wakeup");
+            Response::done()
+        }
+    }
+
+    #[test]
+    fn mounted() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let lc = Loop::<Mount<Inner, App>>::new(&Config::new()).unwrap();
+        let mut inst = lc.instantiate(App {
+            name: "app",
+            sub: Sub { hits: 0, log: log.clone() },
+        });
+        inst.add_machine_with(|scope| {
+            assert_eq!(scope.name, "app");
+            scope.project(|app| &mut app.sub).hits = 10;
+            let deadline = scope.now() + Duration::from_millis(1);
+            Response::ok(Mount::new(Inner)).deadline(deadline)
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(*log.borrow(), vec!["timeout 11", "wakeup 12"]);
+    }
+}
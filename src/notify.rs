@@ -1,7 +1,13 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
 use mio::Token;
 use mio::deprecated::Sender;
 
 use handler::{Notify};
+use tokens;
 
 quick_error! {
     /// Error when waking up a connection
@@ -26,9 +32,159 @@ quick_error! {
         Closed {
             description("Notification queue is close")
         }
+        /// The token is out of range of the loop, e.g. the notifier was
+        /// created for another loop
+        InvalidToken {
+            description("The token doesn't belong to the event loop")
+        }
+    }
+}
+
+
+/// What to do when the wakeup queue is full
+///
+/// See `Config::wakeup_queue_bound`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeupOverflow {
+    /// `Notifier::wakeup` returns `WakeupError::Full` (the default)
+    Fail,
+    /// The wakeup is silently dropped
+    Drop,
+}
+
+/// No wakeup is pending for the slot
+const IDLE: usize = 0;
+const NORMAL: usize = 1;
+const URGENT: usize = 2;
+
+#[derive(Debug)]
+struct Lanes {
+    urgent: VecDeque<Token>,
+    normal: VecDeque<Token>,
+    /// Acknowledgements to send when the wakeups are processed, along
+    /// with the generation of the state machine
+    acks: Vec<(Token, u64, AckSender)>,
+    /// The loop is notified to process the queue
+    signalled: bool,
+    /// The loop is stopped
+    closed: bool,
+}
+
+/// The outcome of the wakeup, see `Notifier::wakeup_with_ack`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeupStatus {
+    /// `Machine::wakeup` was called (it might be coalesced with other
+    /// wakeups of the same state machine)
+    Dispatched,
+    /// There was no state machine with this token
+    Vacant,
+    /// The wakeup was dropped because the loop stopped or the queue was
+    /// full (with `WakeupOverflow::Drop`)
+    Dropped,
+}
+
+#[derive(Debug)]
+struct AckState {
+    status: Mutex<Option<WakeupStatus>>,
+    done: Condvar,
+}
+
+/// A handle to find out the outcome of the wakeup
+///
+/// Returned by `Notifier::wakeup_with_ack`. The handle may be polled with
+/// `status()` or waited on from another thread. Don't wait in the thread
+/// of the loop itself, as the wakeup is only processed when the action
+/// returns.
+#[derive(Debug, Clone)]
+pub struct WakeupAck {
+    state: Arc<AckState>,
+}
+
+/// The loop's side of the `WakeupAck`, resolves to `Dropped` when dropped
+#[derive(Debug)]
+pub struct AckSender {
+    state: Option<Arc<AckState>>,
+}
+
+fn ack_pair() -> (WakeupAck, AckSender) {
+    let state = Arc::new(AckState {
+        status: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    (WakeupAck { state: state.clone() }, AckSender { state: Some(state) })
+}
+
+impl AckSender {
+    pub fn resolve(mut self, status: WakeupStatus) {
+        self.set(status);
+    }
+    fn set(&mut self, status: WakeupStatus) {
+        if let Some(state) = self.state.take() {
+            let mut guard = state.status.lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *guard = Some(status);
+            state.done.notify_all();
+        }
+    }
+}
+
+impl Drop for AckSender {
+    fn drop(&mut self) {
+        self.set(WakeupStatus::Dropped);
+    }
+}
+
+impl WakeupAck {
+    /// Returns the outcome of the wakeup, or `None` if it's not known yet
+    pub fn status(&self) -> Option<WakeupStatus> {
+        *self.state.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Blocks until the wakeup is processed by the loop
+    pub fn wait(&self) -> WakeupStatus {
+        let mut guard = self.state.status.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(status) = *guard {
+                return status;
+            }
+            guard = self.state.done.wait(guard)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+    /// Blocks until the wakeup is processed or the timeout expires
+    ///
+    /// Returns `None` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<WakeupStatus> {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.state.status.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(status) = *guard {
+                return Some(status);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            guard = self.state.done.wait_timeout(guard, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
     }
 }
 
+/// The queue of state machines to wake up
+///
+/// Every slot of the loop has a wakeup flag, so a wakeup of the state
+/// machine which has a pending one is a no-op, and the machine is woken up
+/// once however many times `wakeup` is called. The loop is notified
+/// through the mio's channel once per batch of wakeups.
+#[derive(Debug)]
+pub struct WakeupQueue {
+    pending: Vec<AtomicUsize>,
+    lanes: Mutex<Lanes>,
+    bound: usize,
+    overflow: WakeupOverflow,
+}
 
 /// The object used to wakeup unrelated state machine
 ///
@@ -36,27 +192,263 @@ quick_error! {
 #[derive(Clone, Debug)]
 pub struct Notifier {
     token: Token,
+    /// The generation of the state machine, to tell apart the one which
+    /// reused the token
+    generation: u64,
     channel: Sender<Notify>,
+    queue: Option<Arc<WakeupQueue>>,
 }
 
-pub fn create_notifier(token: Token, channel: &Sender<Notify>) -> Notifier {
+/// Creates a notifier, `io_token` is the token encoded with the generation
+/// of the state machine (see `tokens` module)
+pub fn create_notifier(io_token: Token, channel: &Sender<Notify>,
+    queue: Option<&Arc<WakeupQueue>>)
+    -> Notifier
+{
+    let (token, _, generation) = tokens::decode(io_token);
     Notifier {
         token: token,
-        channel: channel.clone()
+        generation: generation,
+        channel: channel.clone(),
+        queue: queue.cloned(),
+    }
+}
+
+fn send(channel: &Sender<Notify>, msg: Notify) -> Result<(), WakeupError> {
+    use mio::deprecated::NotifyError::*;
+    match channel.send(msg) {
+        Ok(()) => Ok(()),
+        Err(Closed(_)) => Err(WakeupError::Closed),
+        Err(Io(_)) => Err(WakeupError::Io),
+        Err(Full(_)) => Err(WakeupError::Full),
+    }
+}
+
+impl WakeupQueue {
+    /// Creates a queue for `slots` state machines, of which `bound` may
+    /// have a pending wakeup
+    pub fn new(slots: usize, bound: usize, overflow: WakeupOverflow)
+        -> WakeupQueue
+    {
+        WakeupQueue {
+            pending: (0..slots).map(|_| AtomicUsize::new(IDLE)).collect(),
+            lanes: Mutex::new(Lanes {
+                urgent: VecDeque::new(),
+                normal: VecDeque::new(),
+                acks: Vec::new(),
+                signalled: false,
+                closed: false,
+            }),
+            bound: bound,
+            overflow: overflow,
+        }
+    }
+    fn push(&self, token: Token, level: usize, channel: &Sender<Notify>,
+        ack: Option<(u64, AckSender)>)
+        -> Result<(), WakeupError>
+    {
+        let flag = match self.pending.get(token.0) {
+            Some(flag) => flag,
+            None => return Err(WakeupError::InvalidToken),
+        };
+        // The flag is cleared by the loop right before the wakeup is
+        // dispatched, so if it's set the machine will be woken up anyway
+        if ack.is_none() && flag.load(Ordering::SeqCst) >= level {
+            return Ok(());
+        }
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        if lanes.closed {
+            return Err(WakeupError::Closed);
+        }
+        let current = flag.load(Ordering::SeqCst);
+        let enqueue = current < level;
+        // Upgrading the pending wakeup to urgent doesn't take a place
+        if enqueue && current == IDLE &&
+            lanes.urgent.len() + lanes.normal.len() >= self.bound
+        {
+            return match self.overflow {
+                WakeupOverflow::Fail => Err(WakeupError::Full),
+                WakeupOverflow::Drop => {
+                    debug!("Wakeup queue is full, wakeup of {:?} dropped",
+                        token);
+                    Ok(())
+                }
+            };
+        }
+        // The acknowledgement is sent on the next run of the queue, even if
+        // the wakeup itself is coalesced with the one taken already
+        if (enqueue || ack.is_some()) && !lanes.signalled {
+            try!(send(channel, Notify::Queue));
+            lanes.signalled = true;
+        }
+        if enqueue {
+            flag.store(level, Ordering::SeqCst);
+            if level == URGENT {
+                lanes.urgent.push_back(token);
+            } else {
+                lanes.normal.push_back(token);
+            }
+        }
+        if let Some((generation, ack)) = ack {
+            lanes.acks.push((token, generation, ack));
+        }
+        Ok(())
+    }
+    /// Takes all the queued tokens and acknowledgements, urgent tokens go
+    /// first
+    ///
+    /// A token may be returned twice if the wakeup was upgraded to urgent,
+    /// use `clear` to find out whether the wakeup is still pending.
+    /// Acknowledgements should be resolved after the tokens are processed.
+    pub fn take(&self) -> (Vec<Token>, Vec<(Token, u64, AckSender)>) {
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        lanes.signalled = false;
+        let Lanes { ref mut urgent, ref mut normal, ref mut acks, .. } =
+            *lanes;
+        (urgent.drain(..).chain(normal.drain(..)).collect(),
+         acks.drain(..).collect())
+    }
+    /// Marks the queue as closed, pending acknowledgements are dropped
+    pub fn close(&self) {
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        lanes.closed = true;
+        lanes.acks.clear();
+    }
+    /// Clears the wakeup flag, returns `true` if the wakeup was pending
+    pub fn clear(&self, token: Token) -> bool {
+        self.pending.get(token.0)
+            .map(|flag| flag.swap(IDLE, Ordering::SeqCst) != IDLE)
+            .unwrap_or(false)
     }
 }
 
 impl Notifier {
     /// Wakeup a state machine
     ///
-    ///
+    /// If the state machine has a pending wakeup, this is a no-op, i.e.
+    /// many wakeups before the loop processes them result in a single
+    /// `Machine::wakeup` call.
     pub fn wakeup(&self) -> Result<(), WakeupError> {
-        use mio::deprecated::NotifyError::*;
-        match self.channel.send(Notify::Fsm(self.token)) {
-            Ok(()) => Ok(()),
-            Err(Closed(_)) => Err(WakeupError::Closed),
-            Err(Io(_)) => Err(WakeupError::Io),
-            Err(Full(_)) => Err(WakeupError::Full),
+        match self.queue {
+            Some(ref queue) => {
+                queue.push(self.token, NORMAL, &self.channel, None)
+            }
+            None => send(&self.channel, Notify::Fsm(self.token)),
+        }
+    }
+    /// Wakeup a state machine before the ones woken up with `wakeup`
+    ///
+    /// The urgent wakeups are processed before the normal ones queued
+    /// at the same time. Otherwise it's same as `wakeup`.
+    pub fn wakeup_urgent(&self) -> Result<(), WakeupError> {
+        match self.queue {
+            Some(ref queue) => {
+                queue.push(self.token, URGENT, &self.channel, None)
+            }
+            None => send(&self.channel, Notify::Fsm(self.token)),
         }
     }
+    /// Wakeup a state machine and get a handle to find out the outcome
+    ///
+    /// The handle resolves to `WakeupStatus::Dispatched` after the
+    /// `Machine::wakeup` of the state machine returns, or to `Vacant` if
+    /// the state machine has exited. Note: the token may be reused by a new
+    /// state machine, which is woken up then, but the handle resolves to
+    /// `Vacant` anyway.
+    pub fn wakeup_with_ack(&self) -> Result<WakeupAck, WakeupError> {
+        let (ack, sender) = ack_pair();
+        match self.queue {
+            Some(ref queue) => {
+                try!(queue.push(self.token, NORMAL, &self.channel,
+                                Some((self.generation, sender))));
+            }
+            None => {
+                try!(send(&self.channel,
+                    Notify::Ack(self.token, self.generation, sender)));
+            }
+        }
+        Ok(ack)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use mio::Token;
+
+    use test_util::event_loop;
+    use WakeupError;
+    use super::{WakeupQueue, WakeupOverflow, WakeupStatus, create_notifier};
+
+    #[test]
+    fn coalesce() {
+        let eloop = event_loop();
+        let queue = Arc::new(WakeupQueue::new(4, 2, WakeupOverflow::Fail));
+        let chan = eloop.channel();
+        let n1 = create_notifier(Token(1), &chan, Some(&queue));
+        let n2 = create_notifier(Token(2), &chan, Some(&queue));
+        let n3 = create_notifier(Token(3), &chan, Some(&queue));
+        for _ in 0..10000 {
+            n1.wakeup().unwrap();
+        }
+        n2.wakeup().unwrap();
+        match n3.wakeup() {
+            Err(WakeupError::Full) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        n2.wakeup_urgent().unwrap();
+        assert_eq!(queue.take().0, vec![Token(2), Token(1), Token(2)]);
+        assert!(queue.clear(Token(2)));
+        assert!(queue.clear(Token(1)));
+        assert!(!queue.clear(Token(2)));
+        n3.wakeup().unwrap();
+        let ack = n3.wakeup_with_ack().unwrap();
+        let (tokens, acks) = queue.take();
+        assert_eq!(tokens, vec![Token(3)]);
+        assert!(queue.clear(Token(3)));
+        assert_eq!(ack.status(), None);
+        for (_, _, sender) in acks {
+            sender.resolve(WakeupStatus::Dispatched);
+        }
+        assert_eq!(ack.wait(), WakeupStatus::Dispatched);
+        let ack = n3.wakeup_with_ack().unwrap();
+        queue.close();
+        assert_eq!(ack.wait_timeout(Duration::from_secs(1)),
+                   Some(WakeupStatus::Dropped));
+        match n1.wakeup() {
+            Err(WakeupError::Closed) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_token() {
+        let eloop = event_loop();
+        let queue = Arc::new(WakeupQueue::new(4, 4, WakeupOverflow::Fail));
+        let chan = eloop.channel();
+        let n = create_notifier(Token(4), &chan, Some(&queue));
+        match n.wakeup() {
+            Err(WakeupError::InvalidToken) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match n.wakeup_with_ack() {
+            Err(WakeupError::InvalidToken) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        assert!(!queue.clear(Token(4)));
+    }
+
+    #[test]
+    fn ack_without_queue() {
+        let eloop = event_loop();
+        let chan = eloop.channel();
+        let n = create_notifier(Token(1), &chan, None);
+        // The acknowledgement is sent to the loop along with the wakeup
+        let ack = n.wakeup_with_ack().unwrap();
+        assert_eq!(ack.status(), None);
+        drop(eloop);
+        assert_eq!(ack.status(), Some(WakeupStatus::Dropped));
+    }
 }
@@ -0,0 +1,651 @@
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::marker::PhantomData;
+#[cfg(target_os = "linux")] use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::Evented;
+use mio::tcp::TcpStream;
+#[cfg(unix)] use mio::deprecated::unix::UnixStream;
+#[cfg(unix)] use mio::unix::UnixReady;
+use void::Void;
+
+use {Machine, Scope, Response, EventSet, PollOpt, GenericScopeExt, Source};
+
+
+/// Size of the buffer for each direction (when splice is not used)
+pub const BUFFER_SIZE: usize = 65536;
+
+const CLIENT: Source = Source(0);
+const UPSTREAM: Source = Source(1);
+
+/// A stream which may be proxied by `Proxy`
+pub trait Stream: Evented + Read + Write {
+    /// Shuts down the writing half of the stream
+    fn shutdown_write(&self) -> io::Result<()>;
+    /// Returns the pending error of the socket (e.g. connection reset)
+    ///
+    /// It's checked when the stream hangs up. Returns `Ok(None)` by default,
+    /// so the hangup is treated as the stream being closed by the peer.
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+    /// Returns a file descriptor which may be used with `splice(2)`
+    ///
+    /// Returns `None` by default, so the data is copied through the buffer
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        TcpStream::take_error(self)
+    }
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write).map(|_| ())
+    }
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// Bytes read from one side but not yet written to the other one
+enum Buffer {
+    Memory {
+        data: Box<[u8]>,
+        start: usize,
+        end: usize,
+    },
+    #[cfg(target_os = "linux")]
+    Pipe(splice::Pipe),
+}
+
+/// One direction of the proxy
+struct Pump {
+    buffer: Buffer,
+    /// The end of stream is read from the source
+    eof: bool,
+    /// The writing half of the destination is shut down (or the
+    /// destination is closed)
+    shut: bool,
+}
+
+/// A state machine which forwards data between two streams
+///
+/// Data is forwarded in both directions, each through a bounded buffer.
+/// When the buffer is full, the proxy stops reading from the source until
+/// the destination accepts some data, so the slow side throttles the fast
+/// one. When one side closes the stream (or shuts down writing), the
+/// writing half of the other side is shut down once the buffer is flushed,
+/// and the proxy keeps forwarding in the other direction. The state
+/// machine exits when both directions are finished.
+///
+/// On Linux the data is moved with `splice(2)` through a pipe, so it's
+/// never copied to the user space (if both streams have `splice_fd`).
+///
+/// The seed is a pair of `(client, upstream)` streams, so the listener
+/// which accepts clients and connects to the upstream may spawn the proxy
+/// right away (the upstream might be still connecting):
+///
+/// ```ignore
+/// let upstream = try!(TcpStream::connect(&addr));
+/// Response::spawn(Fsm::Accept(listener), Seed::Proxy((client, upstream)))
+/// ```
+pub struct Proxy<C, S: Stream> {
+    client: S,
+    upstream: S,
+    to_upstream: Pump,
+    to_client: Pump,
+    /// The client has hung up, so it's not registered any more
+    client_closed: bool,
+    /// The upstream has hung up, so it's not registered any more
+    upstream_closed: bool,
+    phantom: PhantomData<*const C>,
+}
+
+impl Buffer {
+    fn memory() -> Buffer {
+        Buffer::Memory {
+            data: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+    #[cfg(target_os = "linux")]
+    fn new<S: Stream>(src: &S, dst: &S) -> Buffer {
+        if src.splice_fd().is_none() || dst.splice_fd().is_none() {
+            return Buffer::memory();
+        }
+        match splice::Pipe::new() {
+            Ok(pipe) => Buffer::Pipe(pipe),
+            Err(e) => {
+                debug!("Can't create pipe for splice: {}", e);
+                Buffer::memory()
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn new<S: Stream>(_src: &S, _dst: &S) -> Buffer {
+        Buffer::memory()
+    }
+    fn is_empty(&self) -> bool {
+        match *self {
+            Buffer::Memory { start, end, .. } => start == end,
+            #[cfg(target_os = "linux")]
+            Buffer::Pipe(ref pipe) => pipe.is_empty(),
+        }
+    }
+    fn is_full(&self) -> bool {
+        match *self {
+            // the space is reused only when the buffer is flushed
+            Buffer::Memory { ref data, start, end } => {
+                end == data.len() && start != end
+            }
+            #[cfg(target_os = "linux")]
+            Buffer::Pipe(ref pipe) => pipe.is_full(),
+        }
+    }
+    /// Reads a chunk of data from the source, returns `Ok(0)` on the end
+    /// of stream
+    fn fill<S: Stream>(&mut self, src: &mut S) -> io::Result<usize> {
+        match *self {
+            Buffer::Memory { ref mut data, ref mut start, ref mut end } => {
+                if *start == *end {
+                    *start = 0;
+                    *end = 0;
+                }
+                let bytes = try!(src.read(&mut data[*end..]));
+                *end += bytes;
+                Ok(bytes)
+            }
+            #[cfg(target_os = "linux")]
+            Buffer::Pipe(ref mut pipe) => {
+                pipe.fill(src.splice_fd().expect("splice fd"))
+            }
+        }
+    }
+    /// Writes a chunk of data to the destination
+    fn drain<S: Stream>(&mut self, dst: &mut S) -> io::Result<usize> {
+        match *self {
+            Buffer::Memory { ref data, ref mut start, end } => {
+                let bytes = try!(dst.write(&data[*start..end]));
+                *start += bytes;
+                Ok(bytes)
+            }
+            #[cfg(target_os = "linux")]
+            Buffer::Pipe(ref mut pipe) => {
+                pipe.drain(dst.splice_fd().expect("splice fd"))
+            }
+        }
+    }
+}
+
+fn would_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl Pump {
+    fn new(buffer: Buffer) -> Pump {
+        Pump {
+            buffer: buffer,
+            eof: false,
+            shut: false,
+        }
+    }
+    /// Moves data from the source to the destination until either of them
+    /// would block
+    fn run<S: Stream>(&mut self, src: &mut S, dst: &mut S) -> io::Result<()> {
+        if self.shut {
+            return Ok(());
+        }
+        loop {
+            let mut progress = false;
+            if !self.eof && !self.buffer.is_full() {
+                match try!(would_block(self.buffer.fill(src))) {
+                    Some(0) => self.eof = true,
+                    Some(_) => progress = true,
+                    None => {}
+                }
+            }
+            if !self.buffer.is_empty() {
+                match try!(would_block(self.buffer.drain(dst))) {
+                    Some(0) => {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero,
+                            "failed to write to the stream"));
+                    }
+                    Some(_) => progress = true,
+                    None => {}
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        if self.eof && !self.shut && self.buffer.is_empty() {
+            try!(dst.shutdown_write());
+            self.shut = true;
+        }
+        Ok(())
+    }
+    fn wants_read(&self) -> bool {
+        !self.eof && !self.buffer.is_full()
+    }
+    fn wants_write(&self) -> bool {
+        !self.shut && !self.buffer.is_empty()
+    }
+    /// Finishes the pump when the destination is closed
+    ///
+    /// Nobody is going to receive the data, so the buffered data is
+    /// dropped and the source is not read any more.
+    fn close(&mut self) {
+        self.eof = true;
+        self.shut = true;
+    }
+    fn is_finished(&self) -> bool {
+        self.shut
+    }
+}
+
+fn interest(reading: &Pump, writing: &Pump) -> EventSet {
+    let mut events = EventSet::empty();
+    if reading.wants_read() {
+        events.insert(EventSet::readable());
+    }
+    if writing.wants_write() {
+        events.insert(EventSet::writable());
+    }
+    events
+}
+
+/// Returns true if the stream has hung up or has an error
+///
+/// Such events are delivered even if the stream is registered with an
+/// empty interest.
+#[cfg(unix)]
+fn is_closed(events: EventSet) -> bool {
+    let events = UnixReady::from(events);
+    events.is_hup() || events.is_error()
+}
+#[cfg(not(unix))]
+fn is_closed(_events: EventSet) -> bool {
+    false
+}
+
+impl<C, S: Stream> Proxy<C, S> {
+    /// Creates a proxy between two streams
+    pub fn new<G: GenericScopeExt>(client: S, upstream: S, scope: &mut G)
+        -> Response<Proxy<C, S>, Void>
+    {
+        let to_upstream = Buffer::new(&client, &upstream);
+        let to_client = Buffer::new(&upstream, &client);
+        Proxy::with_buffers(client, upstream, to_upstream, to_client, scope)
+    }
+    fn with_buffers<G, N>(client: S, upstream: S,
+        to_upstream: Buffer, to_client: Buffer, scope: &mut G)
+        -> Response<Proxy<C, S>, N>
+        where G: GenericScopeExt,
+    {
+        let proxy = Proxy {
+            client: client,
+            upstream: upstream,
+            to_upstream: Pump::new(to_upstream),
+            to_client: Pump::new(to_client),
+            client_closed: false,
+            upstream_closed: false,
+            phantom: PhantomData,
+        };
+        let result = scope.register_source(&proxy.client, CLIENT,
+                EventSet::readable(), PollOpt::level())
+            .and_then(|()| scope.register_source(&proxy.upstream, UPSTREAM,
+                EventSet::readable() | EventSet::writable(),
+                PollOpt::level()));
+        match result {
+            Ok(()) => Response::ok(proxy),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    /// Returns a reference to the client stream
+    pub fn client(&self) -> &S {
+        &self.client
+    }
+    /// Returns a reference to the upstream stream
+    pub fn upstream(&self) -> &S {
+        &self.upstream
+    }
+    /// Runs pumps which may be unblocked by the events
+    ///
+    /// Readable source or writable destination may unblock the pump. If
+    /// the source is unknown or it's a hangup (or an error), both pumps
+    /// are run, so the data left in the hung up stream is read. Hangups
+    /// themselves are handled by `close`.
+    fn pump(&mut self, events: EventSet, source: Option<Source>)
+        -> io::Result<()>
+    {
+        let Proxy { ref mut client, ref mut upstream,
+                    ref mut to_upstream, ref mut to_client, .. } = *self;
+        let all = !events.is_readable() && !events.is_writable();
+        let (forward, backward) = match source {
+            Some(CLIENT) => (events.is_readable(), events.is_writable()),
+            Some(_) => (events.is_writable(), events.is_readable()),
+            None => (true, true),
+        };
+        if all || forward {
+            try!(to_upstream.run(client, upstream));
+        }
+        if all || backward {
+            try!(to_client.run(upstream, client));
+        }
+        Ok(())
+    }
+    /// Handles a hangup (or an error) of the streams
+    ///
+    /// A pending error of the stream fails the proxy. Otherwise the stream
+    /// is closed by the peer, so the pump writing to it is finished and
+    /// the stream is deregistered, as the hangup is reported even with an
+    /// empty interest. The data left in the stream is read when the other
+    /// side accepts it.
+    fn close(&mut self, source: Option<Source>, scope: &mut Scope<C>)
+        -> io::Result<()>
+    {
+        if source != Some(UPSTREAM) && !self.client_closed {
+            if let Some(e) = try!(self.client.take_error()) {
+                return Err(e);
+            }
+            self.to_client.close();
+            self.client_closed = true;
+            try!(scope.deregister(&self.client));
+        }
+        if source != Some(CLIENT) && !self.upstream_closed {
+            if let Some(e) = try!(self.upstream.take_error()) {
+                return Err(e);
+            }
+            self.to_upstream.close();
+            self.upstream_closed = true;
+            try!(scope.deregister(&self.upstream));
+        }
+        Ok(())
+    }
+    fn step(mut self, events: EventSet, source: Option<Source>,
+            scope: &mut Scope<C>)
+        -> Response<Self, (S, S)>
+    {
+        if let Err(e) = self.pump(events, source) {
+            return Response::error(Box::new(e));
+        }
+        if is_closed(events) {
+            if let Err(e) = self.close(source, scope) {
+                return Response::error(Box::new(e));
+            }
+        }
+        if self.to_upstream.is_finished() && self.to_client.is_finished() {
+            return Response::done();
+        }
+        match self.reregister(scope) {
+            Ok(()) => Response::ok(self),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    fn reregister(&self, scope: &mut Scope<C>) -> io::Result<()> {
+        if !self.client_closed {
+            try!(scope.reregister_source(&self.client, CLIENT,
+                interest(&self.to_upstream, &self.to_client),
+                PollOpt::level()));
+        }
+        if !self.upstream_closed {
+            try!(scope.reregister_source(&self.upstream, UPSTREAM,
+                interest(&self.to_client, &self.to_upstream),
+                PollOpt::level()));
+        }
+        Ok(())
+    }
+}
+
+impl<C, S: Stream> Machine for Proxy<C, S> {
+    type Context = C;
+    type Seed = (S, S);
+
+    fn create((client, upstream): (S, S), scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        let to_upstream = Buffer::new(&client, &upstream);
+        let to_client = Buffer::new(&upstream, &client);
+        Proxy::with_buffers(client, upstream, to_upstream, to_client, scope)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.step(events, None, scope)
+    }
+    fn ready_source(self, events: EventSet, source: Source,
+                    scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.step(events, Some(source), scope)
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        unreachable!("proxy never spawns");
+    }
+    fn timeout(self, _scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("proxy, eof: client {}, upstream {}",
+            self.to_upstream.eof, self.to_client.eof))
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::io;
+    use std::ptr;
+    use std::os::unix::io::RawFd;
+
+    use libc;
+
+    /// A pipe which holds the data moved between sockets by `splice(2)`
+    pub struct Pipe {
+        read: RawFd,
+        write: RawFd,
+        len: usize,
+        capacity: usize,
+    }
+
+    fn check(result: libc::ssize_t) -> io::Result<usize> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let size = unsafe { libc::fcntl(fds[1], libc::F_GETPIPE_SZ) };
+            Ok(Pipe {
+                read: fds[0],
+                write: fds[1],
+                len: 0,
+                capacity: if size > 0 { size as usize } else { 4096 },
+            })
+        }
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+        pub fn is_full(&self) -> bool {
+            self.len >= self.capacity
+        }
+        pub fn fill(&mut self, src: RawFd) -> io::Result<usize> {
+            let bytes = try!(check(unsafe {
+                libc::splice(src, ptr::null_mut(), self.write, ptr::null_mut(),
+                    self.capacity - self.len,
+                    libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+            }));
+            self.len += bytes;
+            Ok(bytes)
+        }
+        pub fn drain(&mut self, dst: RawFd) -> io::Result<usize> {
+            let bytes = try!(check(unsafe {
+                libc::splice(self.read, ptr::null_mut(), dst, ptr::null_mut(),
+                    self.len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+            }));
+            self.len -= bytes;
+            Ok(bytes)
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.read);
+                libc::close(self.write);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::mem;
+    use std::net;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use libc;
+    use mio::tcp::TcpStream;
+    use mio::deprecated::unix::UnixStream;
+
+    use {Loop, Config};
+    use super::{Proxy, Buffer, BUFFER_SIZE};
+
+    fn pair() -> (StdUnixStream, UnixStream) {
+        let (far, near) = StdUnixStream::pair().unwrap();
+        near.set_nonblocking(true).unwrap();
+        (far, unsafe { UnixStream::from_raw_fd(near.into_raw_fd()) })
+    }
+
+    fn proxy(splice: bool) {
+        let (mut client, client_near) = pair();
+        let (mut upstream, upstream_near) = pair();
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        lc.add_machine_with(|scope| {
+            let (a, b) = if splice {
+                (Buffer::new(&client_near, &upstream_near),
+                 Buffer::new(&upstream_near, &client_near))
+            } else {
+                (Buffer::memory(), Buffer::memory())
+            };
+            Proxy::<(), _>::with_buffers(client_near, upstream_near,
+                                         a, b, scope)
+        }).unwrap();
+        let request = vec![b'x'; BUFFER_SIZE * 3];
+        let sent = request.clone();
+        let client = thread::spawn(move || {
+            client.write_all(&sent).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        let server = thread::spawn(move || {
+            let mut request = Vec::new();
+            upstream.read_to_end(&mut request).unwrap();
+            upstream.write_all(b"response").unwrap();
+            request
+        });
+        lc.run(()).unwrap();
+        assert_eq!(server.join().unwrap(), request);
+        assert_eq!(client.join().unwrap(), "response");
+    }
+
+    #[test]
+    fn memory() {
+        proxy(false);
+    }
+
+    #[test]
+    fn splice() {
+        proxy(true);
+    }
+
+    fn tcp_pair(listener: &net::TcpListener) -> (net::TcpStream, TcpStream) {
+        let far = net::TcpStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (near, _) = listener.accept().unwrap();
+        (far, TcpStream::from_stream(near).unwrap())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reset(stream: net::TcpStream) {
+        // Zero linger timeout makes `close` send RST
+        let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+        let res = unsafe {
+            libc::setsockopt(stream.as_raw_fd(),
+                libc::SOL_SOCKET, libc::SO_LINGER,
+                &linger as *const _ as *const libc::c_void,
+                mem::size_of::<libc::linger>() as libc::socklen_t)
+        };
+        assert_eq!(res, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn peer_reset() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, client_near) = tcp_pair(&listener);
+        let (mut upstream, upstream_near) = tcp_pair(&listener);
+        let (tx, rx) = mpsc::channel();
+        // The loop keeps running if the reset is not handled
+        thread::spawn(move || {
+            let mut lc = Loop::new(&Config::new()).unwrap();
+            lc.on_exit(move |_, _, _, reason| {
+                tx.send(reason.to_string()).unwrap();
+            });
+            lc.add_machine_with(|scope| {
+                Proxy::<(), _>::new(client_near, upstream_near, scope)
+            }).unwrap();
+            lc.run(()).unwrap();
+        });
+        client.write_all(b"request").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut request = Vec::new();
+        upstream.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+        // The proxy only waits for the response from the upstream now,
+        // so the client is registered with an empty interest
+        reset(client);
+        let reason = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // Linux reports EPIPE, as the client has already sent FIN
+        assert!(reason.starts_with("error: "), "{}", reason);
+    }
+}
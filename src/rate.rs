@@ -0,0 +1,283 @@
+use std::cmp::{min, max};
+use std::time::Duration;
+
+use loop_time::millis_between;
+use Time;
+
+
+/// A token bucket rate limiter
+///
+/// The bucket holds up to `burst` tokens and is refilled by `rate` tokens
+/// per second. A token may be anything: a request, a byte, a new
+/// connection. The refill is computed from the loop `Time` lazily, so the
+/// bucket costs nothing when unused and is cheap to evaluate with
+/// `Scope::now()`.
+///
+/// When there are not enough tokens, the time when they will be available
+/// is returned, so it may be passed to `Response::deadline` directly:
+///
+/// ```ignore
+/// fn timeout(self, scope: &mut Scope<Context>) -> Response<Self, Void> {
+///     let now = scope.now();
+///     match scope.requests.try_take(1, now) {
+///         Ok(()) => self.send_request(scope),
+///         Err(time) => Response::ok(self).deadline(time),
+///     }
+/// }
+/// ```
+///
+/// A limiter for the whole loop (e.g. `requests` above) is just a field of
+/// the context, as all state machines of the loop run in the same thread.
+/// Per-connection limiters are fields of the state machines.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens per second (i.e. thousandths of a token per millisecond)
+    rate: u64,
+    /// Capacity in thousandths of a token
+    capacity: i64,
+    /// Thousandths of a token, negative when a request bigger than the
+    /// capacity was let through
+    balance: i64,
+    updated: Time,
+}
+
+/// A leaky bucket, which smooths requests to a constant rate
+///
+/// Every request is put into the bucket, which leaks at `rate` units per
+/// second. The request may proceed when everything put before it has
+/// leaked out, so requests are spread evenly instead of going in bursts.
+/// When the bucket is full (i.e. more than `capacity` units are waiting),
+/// the request is rejected.
+#[derive(Debug, Clone)]
+pub struct LeakyBucket {
+    /// Units per second (i.e. thousandths of a unit per millisecond)
+    rate: u64,
+    /// Capacity in thousandths of a unit
+    capacity: u64,
+    /// Thousandths of a unit in the bucket
+    level: u64,
+    updated: Time,
+}
+
+/// Milliseconds needed to get `amount` thousandths of a unit at the `rate`
+fn wait_millis(amount: u64, rate: u64) -> Duration {
+    let round_up = if amount % rate == 0 { 0 } else { 1 };
+    Duration::from_millis(amount / rate + round_up)
+}
+
+/// Converts units to thousandths of a unit, saturating on overflow
+fn thousandths(units: u64) -> i64 {
+    min(units.saturating_mul(1000), i64::MAX as u64) as i64
+}
+
+fn elapsed(from: Time, to: Time) -> u64 {
+    max(millis_between(from, to), 0) as u64
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    ///
+    /// # Panics
+    ///
+    /// When the rate is zero
+    pub fn new(rate: u64, burst: u64, now: Time) -> TokenBucket {
+        assert!(rate > 0, "rate must be positive");
+        TokenBucket {
+            rate: rate,
+            capacity: thousandths(burst),
+            balance: thousandths(burst),
+            updated: now,
+        }
+    }
+    fn refill(&mut self, now: Time) {
+        let ms = elapsed(self.updated, now);
+        if ms > 0 {
+            let room = (self.capacity - self.balance) as u64;
+            self.balance += min(ms.saturating_mul(self.rate), room) as i64;
+            // The fraction of a millisecond is left for the next refill
+            self.updated = self.updated + Duration::from_millis(ms);
+        }
+    }
+    /// Returns the number of whole tokens available
+    pub fn available(&mut self, now: Time) -> u64 {
+        self.refill(now);
+        max(self.balance / 1000, 0) as u64
+    }
+    /// Returns the time when `tokens` will be available
+    ///
+    /// Returns `now` if they are available right away. Requests bigger than
+    /// the burst are allowed when the bucket is full.
+    pub fn ready_at(&mut self, tokens: u64, now: Time) -> Time {
+        self.refill(now);
+        let needed = min(thousandths(tokens), self.capacity);
+        if self.balance >= needed {
+            now
+        } else {
+            let debt = (needed - self.balance) as u64;
+            now.saturating_add(wait_millis(debt, self.rate))
+        }
+    }
+    /// Takes `tokens` from the bucket, or returns the time when they will
+    /// be available
+    ///
+    /// A request bigger than the burst is let through when the bucket is
+    /// full, the next requests wait until the debt is paid off.
+    pub fn try_take(&mut self, tokens: u64, now: Time) -> Result<(), Time> {
+        let time = self.ready_at(tokens, now);
+        if time > now {
+            return Err(time);
+        }
+        self.balance -= thousandths(tokens);
+        Ok(())
+    }
+    /// Takes as many tokens as available, but not more than `max`
+    ///
+    /// Useful to limit bandwidth: read or write at most the returned number
+    /// of bytes, and `give_back` the ones which weren't used.
+    pub fn take_up_to(&mut self, max: u64, now: Time) -> u64 {
+        let tokens = min(self.available(now), max);
+        self.balance -= thousandths(tokens);
+        tokens
+    }
+    /// Returns unused tokens to the bucket
+    pub fn give_back(&mut self, tokens: u64) {
+        self.balance = min(self.capacity,
+                           self.balance.saturating_add(thousandths(tokens)));
+    }
+}
+
+impl LeakyBucket {
+    /// Creates an empty bucket
+    ///
+    /// # Panics
+    ///
+    /// When the rate is zero
+    pub fn new(rate: u64, capacity: u64, now: Time) -> LeakyBucket {
+        assert!(rate > 0, "rate must be positive");
+        LeakyBucket {
+            rate: rate,
+            capacity: capacity.saturating_mul(1000),
+            level: 0,
+            updated: now,
+        }
+    }
+    fn leak(&mut self, now: Time) {
+        let ms = elapsed(self.updated, now);
+        if ms > 0 {
+            self.level = self.level.saturating_sub(
+                ms.saturating_mul(self.rate));
+            // The fraction of a millisecond is left for the next leak
+            self.updated = self.updated + Duration::from_millis(ms);
+        }
+    }
+    /// Puts a request of `units` into the bucket
+    ///
+    /// Returns the time when the request may proceed, or `None` if the
+    /// bucket would overflow (the request is not put into the bucket then).
+    /// A request bigger than the capacity is accepted only by an empty
+    /// bucket.
+    pub fn add(&mut self, units: u64, now: Time) -> Option<Time> {
+        self.leak(now);
+        let amount = units.saturating_mul(1000);
+        if self.level.saturating_add(amount) > self.capacity
+            && self.level > 0
+        {
+            return None;
+        }
+        let start = self.level;
+        self.level = self.level.saturating_add(amount);
+        if start == 0 {
+            Some(now)
+        } else {
+            Some(now.saturating_add(wait_millis(start, self.rate)))
+        }
+    }
+    /// Returns the number of units waiting in the bucket (rounded up)
+    pub fn level(&mut self, now: Time) -> u64 {
+        self.leak(now);
+        self.level / 1000 + if self.level % 1000 == 0 { 0 } else { 1 }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use Time;
+    use super::{TokenBucket, LeakyBucket};
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Time::zero();
+        let mut bucket = TokenBucket::new(10, 2, start);
+        assert_eq!(bucket.try_take(1, start), Ok(()));
+        assert_eq!(bucket.try_take(1, start), Ok(()));
+        assert_eq!(bucket.try_take(1, start), Err(start + ms(100)));
+        assert_eq!(bucket.try_take(1, start + ms(99)), Err(start + ms(100)));
+        assert_eq!(bucket.try_take(1, start + ms(100)), Ok(()));
+        assert_eq!(bucket.available(start + ms(10000)), 2);
+        // bigger than burst, let through when full
+        assert_eq!(bucket.try_take(5, start + ms(10000)), Ok(()));
+        assert_eq!(bucket.ready_at(1, start + ms(10000)),
+                   start + ms(10000 + 400));
+        assert_eq!(bucket.take_up_to(100, start + ms(10400)), 1);
+    }
+
+    #[test]
+    fn leaky_bucket() {
+        let start = Time::zero();
+        let mut bucket = LeakyBucket::new(100, 3, start);
+        assert_eq!(bucket.add(1, start), Some(start));
+        assert_eq!(bucket.add(1, start), Some(start + ms(10)));
+        assert_eq!(bucket.add(1, start), Some(start + ms(20)));
+        assert_eq!(bucket.add(1, start), None);
+        assert_eq!(bucket.level(start + ms(15)), 2);
+        assert_eq!(bucket.add(1, start + ms(15)), Some(start + ms(30)));
+        assert_eq!(bucket.level(start + ms(1000)), 0);
+    }
+
+    #[test]
+    fn fractional_millis() {
+        let start = Time::zero();
+        let us = Duration::from_micros;
+        let mut bucket = TokenBucket::new(1000, 10, start);
+        assert_eq!(bucket.try_take(10, start), Ok(()));
+        assert_eq!(bucket.available(start + us(1500)), 1);
+        assert_eq!(bucket.available(start + us(3000)), 3);
+        let mut bucket = LeakyBucket::new(1000, 10, start);
+        assert_eq!(bucket.add(10, start), Some(start));
+        assert_eq!(bucket.level(start + us(1500)), 9);
+        assert_eq!(bucket.level(start + us(3000)), 7);
+    }
+
+    #[test]
+    fn huge_amounts() {
+        let start = Time::zero();
+        let mut bucket = TokenBucket::new(1, u64::max_value(), start);
+        assert_eq!(bucket.try_take(u64::max_value(), start), Ok(()));
+        bucket.give_back(u64::max_value());
+        assert_eq!(bucket.try_take(1, start), Ok(()));
+        let mut bucket = LeakyBucket::new(1, u64::max_value(), start);
+        assert_eq!(bucket.add(u64::max_value(), start), Some(start));
+        assert!(bucket.level(start) > 0);
+    }
+
+    #[test]
+    fn huge_debt() {
+        let start = Time::zero();
+        // the debt takes longer to pay off than the loop time can represent
+        let mut bucket = TokenBucket::new(1, 1, start);
+        assert_eq!(bucket.try_take(u64::max_value(), start), Ok(()));
+        let time = bucket.ready_at(1, start);
+        assert!(time > start + Duration::from_secs(86400 * 365 * 1000));
+        assert_eq!(bucket.try_take(1, start + ms(10000)), Err(time));
+        let mut bucket = LeakyBucket::new(1, u64::max_value(), start);
+        assert_eq!(bucket.add(u64::max_value(), start), Some(start));
+        let time = bucket.add(1, start).unwrap();
+        assert!(time > start + Duration::from_secs(86400 * 365 * 1000));
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::{self, Read, Write};
+
+use mio::Evented;
+
+use {EventSet, PollOpt, GenericScope};
+
+
+/// An I/O object registered in edge-triggered mode which tracks readiness
+///
+/// With `PollOpt::edge()` the loop notifies the state machine only when
+/// the readiness changes, so the machine must keep reading (writing) until
+/// `WouldBlock`, or remember that the socket is still readable (writable)
+/// until it gets back to it. This wrapper does the latter: pass every
+/// `EventSet` received in `Machine::ready` to `update`, and the readiness
+/// bits are cleared when reading (writing) returns `WouldBlock`.
+///
+/// ```ignore
+/// fn ready(mut self, events: EventSet, scope: &mut Scope<C>)
+///     -> Response<Self, Void>
+/// {
+///     self.sock.update(events);
+///     while self.sock.is_readable() {
+///         match self.sock.read(&mut buf) {
+///             Ok(0) => return Response::done(),
+///             Ok(n) => { /* process data */ }
+///             Err(ref e) if e.kind() == WouldBlock => {}  // bit cleared
+///             Err(e) => return Response::error(Box::new(e)),
+///         }
+///     }
+///     Response::ok(self)
+/// }
+/// ```
+///
+/// For objects that are not `Read` or `Write` (e.g. listeners), use
+/// `clear_readable` and `clear_writable` on `WouldBlock` manually.
+#[derive(Debug)]
+pub struct Tracked<E: Evented> {
+    io: E,
+    readiness: EventSet,
+}
+
+fn is_would_block<T>(result: &io::Result<T>) -> bool {
+    match *result {
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+impl<E: Evented> Tracked<E> {
+    /// Registers the object in edge-triggered mode
+    ///
+    /// The object is considered not ready until the first `update`
+    pub fn register<S: GenericScope>(io: E, interest: EventSet,
+                                     scope: &mut S)
+        -> io::Result<Tracked<E>>
+    {
+        try!(scope.register(&io, interest, PollOpt::edge()));
+        Ok(Tracked {
+            io: io,
+            readiness: EventSet::empty(),
+        })
+    }
+    /// Changes the interest of the object
+    ///
+    /// The readiness accumulated so far is kept
+    pub fn reregister<S: GenericScope>(&self, interest: EventSet,
+                                       scope: &mut S)
+        -> io::Result<()>
+    {
+        scope.reregister(&self.io, interest, PollOpt::edge())
+    }
+    /// Deregisters the object and returns it
+    pub fn deregister<S: GenericScope>(self, scope: &mut S)
+        -> io::Result<E>
+    {
+        try!(scope.deregister(&self.io));
+        Ok(self.io)
+    }
+    /// Adds the events received by the state machine to the readiness
+    pub fn update(&mut self, events: EventSet) {
+        self.readiness.insert(events);
+    }
+    /// Returns the readiness accumulated so far
+    pub fn readiness(&self) -> EventSet {
+        self.readiness
+    }
+    /// Returns true if the object may be read without blocking
+    pub fn is_readable(&self) -> bool {
+        self.readiness.is_readable()
+    }
+    /// Returns true if the object may be written without blocking
+    pub fn is_writable(&self) -> bool {
+        self.readiness.is_writable()
+    }
+    /// Marks the object as not readable (e.g. on `WouldBlock` from accept)
+    pub fn clear_readable(&mut self) {
+        self.readiness.remove(EventSet::readable());
+    }
+    /// Marks the object as not writable
+    pub fn clear_writable(&mut self) {
+        self.readiness.remove(EventSet::writable());
+    }
+    /// Returns a reference to the underlying object
+    pub fn get_ref(&self) -> &E {
+        &self.io
+    }
+    /// Returns a mutable reference to the underlying object
+    ///
+    /// Readiness is not updated for I/O done through the reference
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.io
+    }
+    /// Returns the underlying object, without deregistering it
+    pub fn into_inner(self) -> E {
+        self.io
+    }
+}
+
+impl<E: Evented + Read> Read for Tracked<E> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.io.read(buf);
+        if is_would_block(&result) {
+            self.clear_readable();
+        }
+        result
+    }
+}
+
+impl<E: Evented + Write> Write for Tracked<E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.io.write(buf);
+        if is_would_block(&result) {
+            self.clear_writable();
+        }
+        result
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.io.flush();
+        if is_would_block(&result) {
+            self.clear_writable();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use mio::{Poll, Token, Ready, PollOpt, Evented};
+
+    use EventSet;
+    use super::Tracked;
+
+    struct Pipe {
+        data: Vec<u8>,
+    }
+
+    impl Evented for Pipe {
+        fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt)
+            -> io::Result<()>
+        {
+            Ok(())
+        }
+        fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt)
+            -> io::Result<()>
+        {
+            Ok(())
+        }
+        fn deregister(&self, _: &Poll) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = (&self.data[..]).read(buf).unwrap();
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn clear_on_would_block() {
+        let mut pipe = Tracked {
+            io: Pipe { data: b"hello".to_vec() },
+            readiness: EventSet::empty(),
+        };
+        assert!(!pipe.is_readable());
+        pipe.update(EventSet::readable() | EventSet::writable());
+        let mut buf = [0u8; 3];
+        assert_eq!(pipe.read(&mut buf).unwrap(), 3);
+        assert!(pipe.is_readable());
+        assert_eq!(pipe.read(&mut buf).unwrap(), 2);
+        assert!(pipe.is_readable());
+        assert!(pipe.read(&mut buf).is_err());
+        assert!(!pipe.is_readable());
+        assert!(pipe.is_writable());
+        assert!(pipe.write(b"x").is_err());
+        assert!(!pipe.is_writable());
+        pipe.update(EventSet::writable());
+        assert!(pipe.is_writable());
+        assert!(!pipe.is_readable());
+    }
+}
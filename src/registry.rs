@@ -0,0 +1,121 @@
+//! Tracking of the I/O objects registered by state machines
+//!
+//! When the state machine exits, the objects it has registered with
+//! `register_tracked` and left registered (e.g. a shared or a duplicated
+//! file descriptor) are deregistered by the loop. Otherwise a
+//! level-triggered object would keep waking up the loop forever.
+//!
+//! Every registration is recorded along with the identity (device and
+//! inode) of the file. The state machine may close the file without
+//! deregistering it, and the descriptor may be reused for another file
+//! then, so the descriptor is only deregistered if it still refers to the
+//! same file.
+//!
+//! The registration is keyed by the descriptor, so if the state machine
+//! registers a duplicated descriptor and closes it without deregistering,
+//! the loop has no means to remove it from the poller (it's removed by the
+//! kernel only when all the duplicates are closed). Events of such object
+//! are still filtered out by the generation encoded in the token.
+pub use self::imp::Registry;
+
+
+#[cfg(unix)]
+mod imp {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::{RawFd, FromRawFd};
+
+    use mio::Token;
+    use mio::unix::EventedFd;
+    use mio::deprecated::{EventLoop, Handler as MioHandler};
+
+    /// Device and inode of the file
+    type Identity = (u64, u64);
+
+    fn identity(fd: RawFd) -> Option<Identity> {
+        // The file is only borrowed, so it must not be closed
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        file.metadata().ok().map(|m| (m.dev(), m.ino()))
+    }
+
+    /// File descriptors registered by every state machine
+    pub struct Registry {
+        machines: HashMap<Token, Vec<RawFd>>,
+        fds: HashMap<RawFd, (Token, Identity)>,
+    }
+
+    impl Registry {
+        pub fn new() -> Registry {
+            Registry {
+                machines: HashMap::new(),
+                fds: HashMap::new(),
+            }
+        }
+        /// Records that the state machine has registered the descriptor
+        ///
+        /// If the descriptor was registered by another state machine, it's
+        /// owned by this one from now on.
+        pub fn add(&mut self, token: Token, fd: RawFd) {
+            let id = match identity(fd) {
+                Some(id) => id,
+                None => return,
+            };
+            self.fds.insert(fd, (token, id));
+            let fds = self.machines.entry(token).or_insert_with(Vec::new);
+            if !fds.contains(&fd) {
+                fds.push(fd);
+            }
+        }
+        /// Forgets the descriptor which is deregistered explicitly
+        pub fn remove(&mut self, fd: RawFd) {
+            self.fds.remove(&fd);
+        }
+        /// Deregisters all the descriptors left by the state machine
+        pub fn release<H>(&mut self, eloop: &mut EventLoop<H>, token: Token)
+            where H: MioHandler
+        {
+            let fds = match self.machines.remove(&token) {
+                Some(fds) => fds,
+                None => return,
+            };
+            for fd in fds {
+                match self.fds.get(&fd) {
+                    Some(&(owner, _)) if owner == token => {}
+                    // Deregistered or taken over by another state machine
+                    _ => continue,
+                }
+                let (_, id) = self.fds.remove(&fd).unwrap();
+                if identity(fd) != Some(id) {
+                    // Closed, and maybe reused for another file
+                    continue;
+                }
+                debug!("Deregistering fd {} left by {:?}", fd, token);
+                // Fails if the file was closed and opened again, so it's
+                // not registered anymore, that's fine
+                eloop.deregister(&EventedFd(&fd)).ok();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use mio::Token;
+    use mio::deprecated::{EventLoop, Handler as MioHandler};
+
+    /// There are no file descriptors to track
+    pub struct Registry;
+
+    impl Registry {
+        pub fn new() -> Registry {
+            Registry
+        }
+        pub fn release<H>(&mut self, _eloop: &mut EventLoop<H>,
+            _token: Token)
+            where H: MioHandler
+        {
+        }
+    }
+}
@@ -10,7 +10,9 @@ use {Response, Time};
 pub enum ResponseImpl<M, N> {
     Normal(M),
     Deadline(M, Time),
-    Spawn(M, N),
+    // the flag is true if the children are linked to the parent
+    Spawn(M, N, bool),
+    SpawnMany(M, Box<Vec<N>>, bool),
     Error(Box<Error>),
     Done,
 }
@@ -20,7 +22,46 @@ impl<M: Sized, N:Sized> Response<M, N> {
         Response(ResponseImpl::Normal(machine))
     }
     pub fn spawn(machine: M, result: N) -> Response<M, N> {
-        Response(ResponseImpl::Spawn(machine, result))
+        Response(ResponseImpl::Spawn(machine, result, false))
+    }
+    /// Spawn multiple state machines at once
+    ///
+    /// All the seeds are processed by the loop in one pass. Failures are
+    /// reported by calling `Machine::spawn_error` for each failed seed
+    /// and then `Machine::spawned` is called once (if at least one state
+    /// machine was created).
+    ///
+    /// This is useful for accepting a burst of connections. If the iterator
+    /// is empty, this is equivalent to `Response::ok(machine)`.
+    pub fn spawn_many<I>(machine: M, seeds: I) -> Response<M, N>
+        where I: IntoIterator<Item=N>
+    {
+        let mut seeds = seeds.into_iter().collect::<Vec<_>>();
+        match seeds.len() {
+            0 => Response(ResponseImpl::Normal(machine)),
+            1 => Response(ResponseImpl::Spawn(machine,
+                seeds.pop().unwrap(), false)),
+            _ => Response(ResponseImpl::SpawnMany(machine,
+                Box::new(seeds), false)),
+        }
+    }
+    /// Link the state machines spawned by this response to the parent
+    ///
+    /// When a linked child exits, the `Machine::child_exited` action is
+    /// called on the parent with the token of the child and the reason of
+    /// the exit. If the parent has already exited, the notification is
+    /// silently dropped.
+    ///
+    /// This is a no-op if the response doesn't spawn anything, so it's
+    /// fine to use with `Response::spawn_many` with an empty iterator.
+    pub fn linked(self) -> Response<M, N> {
+        use self::ResponseImpl::*;
+        let imp = match self.0 {
+            Spawn(m, n, _) => Spawn(m, n, true),
+            SpawnMany(m, n, _) => SpawnMany(m, n, true),
+            other => other,
+        };
+        Response(imp)
     }
     pub fn done() -> Response<M, N> {
         Response::<M, N>(ResponseImpl::Done)
@@ -30,10 +71,9 @@ impl<M: Sized, N:Sized> Response<M, N> {
     ///
     /// If `rotor` was compiled with the `log_errors` feature, the error will
     /// be logged on the warning level.
-
-    // TODO add this documentation once mio has upgraded to slab 0.2.0
-    // Additionally, if this response is returned from `Machine::create`,
-    // the error is passed to `Machine::spawn_error`.
+    ///
+    /// Additionally, if this response is returned from `Machine::create`,
+    /// the error is passed to `Machine::spawn_error` of the parent.
     pub fn error(e: Box<Error>) -> Response<M, N> {
         Response::<M, N>(ResponseImpl::Error(e))
     }
@@ -42,7 +82,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         let imp = match self.0 {
             ResponseImpl::Normal(x) => ResponseImpl::Deadline(x, time),
             ResponseImpl::Deadline(x, _) => ResponseImpl::Deadline(x, time),
-            ResponseImpl::Spawn(..) => {
+            ResponseImpl::Spawn(..) | ResponseImpl::SpawnMany(..) => {
                 panic!("You can't attach a deadline/timeout to the \
                     Response::spawn(). The `spawn` action is synchronous \
                     you must set a deadline in the `spawned` handler."); }
@@ -63,16 +103,23 @@ impl<M: Sized, N:Sized> Response<M, N> {
     ///
     /// Usually it's okay to use constructor of wrapper state machine
     /// here as a mapper
-    pub fn map<T, U,  S, R>(self, self_mapper: S, result_mapper: R)
+    ///
+    /// The `result_mapper` is called for every seed of the response created
+    /// by `Response::spawn_many`.
+    pub fn map<T, U,  S, R>(self, self_mapper: S, mut result_mapper: R)
         -> Response<T, U>
         where S: FnOnce(M) -> T,
-              R: FnOnce(N) -> U,
+              R: FnMut(N) -> U,
     {
         use self::ResponseImpl::*;
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
             Deadline(m, time) => Deadline(self_mapper(m), time),
-            Spawn(m, n) => Spawn(self_mapper(m), result_mapper(n)),
+            Spawn(m, n, l) => Spawn(self_mapper(m), result_mapper(n), l),
+            SpawnMany(m, n, l) => {
+                SpawnMany(self_mapper(m),
+                    Box::new(n.into_iter().map(result_mapper).collect()), l)
+            }
             Done => Done,
             Error(e) => Error(e),
         };
@@ -89,7 +136,8 @@ impl<M: Sized, N:Sized> Response<M, N> {
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
             Deadline(m, time) => Deadline(self_mapper(m), time),
-            Spawn(m, n) => Spawn(self_mapper(m), n),
+            Spawn(m, n, l) => Spawn(self_mapper(m), n, l),
+            SpawnMany(m, n, l) => SpawnMany(self_mapper(m), n, l),
             Done => Done,
             Error(e) => Error(e),
         };
@@ -106,6 +154,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
             Normal(..) => false,
             Deadline(..) => false,
             Spawn(..) => false,
+            SpawnMany(..) => false,
             Done => true,
             Error(..) => true,
         }
@@ -122,6 +171,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
             Normal(..) => None,
             Deadline(..) => None,
             Spawn(..) => None,
+            SpawnMany(..) => None,
             Done => None,
             Error(ref e) => Some(&**e),
         }
@@ -149,11 +199,25 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
     /// If the response is not `spawn`, the function panics.
     pub fn expect_spawn(self) -> (M, N) {
         match self.0 {
-            ResponseImpl::Spawn(x, y) => (x, y),
+            ResponseImpl::Spawn(x, y, _) => (x, y),
             me => panic!("expected spawn (`Response::spawn(x)`), \
                 got {:?} instead", me),
         }
     }
+    /// Return a machine and all seeds if response is created with
+    /// `Response::spawn_many(..)` (or `Response::spawn(..)`)
+    ///
+    /// *Use only for unit tests*
+    ///
+    /// If the response is not `spawn` or `spawn_many`, the function panics.
+    pub fn expect_spawn_many(self) -> (M, Vec<N>) {
+        match self.0 {
+            ResponseImpl::Spawn(x, y, _) => (x, vec![y]),
+            ResponseImpl::SpawnMany(x, y, _) => (x, *y),
+            me => panic!("expected spawn (`Response::spawn_many(x)`), \
+                got {:?} instead", me),
+        }
+    }
     /// Returns if response created with `Response::done()`
     ///
     /// *Use only for unit tests*
@@ -181,22 +245,52 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
 }
 
 pub fn decompose<M, N>(token: Token, res: Response<M, N>)
-    -> (Result<M, Option<Box<Error>>>, Option<N>, Option<Time>)
+    -> (Result<M, Option<Box<Error>>>, Vec<N>, Option<Time>)
 {
     match res.0 {
-        ResponseImpl::Normal(m) => (Ok(m), None, None),
-        ResponseImpl::Deadline(m, time) => (Ok(m), None, Some(time)),
-        ResponseImpl::Spawn(m, n) => (Ok(m), Some(n), None),
-        ResponseImpl::Done => (Err(None), None, None),
+        ResponseImpl::Normal(m) => (Ok(m), Vec::new(), None),
+        ResponseImpl::Deadline(m, time) => (Ok(m), Vec::new(), Some(time)),
+        ResponseImpl::Spawn(m, n, _) => (Ok(m), vec![n], None),
+        ResponseImpl::SpawnMany(m, n, _) => (Ok(m), *n, None),
+        ResponseImpl::Done => (Err(None), Vec::new(), None),
         ResponseImpl::Error(e) => {
             if cfg!(feature = "log_errors") {
                 warn!("State machine {:?} exited with error: {}", token, e);
             }
-            (Err(Some(e)), None, None)
+            (Err(Some(e)), Vec::new(), None)
         }
     }
 }
 
+/// Returns true if the spawned children must be linked to the parent
+pub fn is_linked<M, N>(res: &Response<M, N>) -> bool {
+    match res.0 {
+        ResponseImpl::Spawn(_, _, linked) => linked,
+        ResponseImpl::SpawnMany(_, _, linked) => linked,
+        _ => false,
+    }
+}
+
+/// Returns the deadline set in the response
+pub fn response_deadline<M, N>(res: &Response<M, N>) -> Option<Time> {
+    match res.0 {
+        ResponseImpl::Deadline(_, time) => Some(time),
+        _ => None,
+    }
+}
+
+/// Returns short description of the response, useful for logging
+pub fn response_kind<M, N>(res: &Response<M, N>) -> &'static str {
+    match res.0 {
+        ResponseImpl::Normal(..) => "ok",
+        ResponseImpl::Deadline(..) => "deadline",
+        ResponseImpl::Spawn(..) => "spawn",
+        ResponseImpl::SpawnMany(..) => "spawn_many",
+        ResponseImpl::Done => "done",
+        ResponseImpl::Error(..) => "error",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::Response;
@@ -205,4 +299,16 @@ mod tests {
     fn size_of_response() {
         assert_eq!(::std::mem::size_of::<Response<u64, u64>>(), 24)
     }
+
+    #[test]
+    fn map() {
+        // `map` accepts closures which move captured values
+        let suffix = String::from("!");
+        let resp = Response::<u64, u64>::spawn(1, 2)
+            .map(|m| m + 1, move |n| format!("{}{}", n, suffix));
+        assert_eq!(resp.expect_spawn(), (2, String::from("2!")));
+        let resp = Response::<u64, u64>::spawn_many(1, vec![2, 3])
+            .map(|m| m + 1, |n| n * 10);
+        assert_eq!(resp.expect_spawn_many(), (2, vec![20, 30]));
+    }
 }
@@ -1,14 +1,19 @@
 use std::io;
+use std::sync::Arc;
 use std::ops::{Deref, DerefMut};
 use std::time::{SystemTime};
+#[cfg(unix)] use std::os::unix::io::AsRawFd;
 
 use mio::Token;
 use mio::deprecated::Sender;
 
 use handler::Notify;
+use introspect::{Introspect, MachineInfo, Counters};
 use loop_api::LoopApi;
-use loop_time::{estimate_system_time};
-use notify::create_notifier;
+use loop_time::{estimate_system_time, estimate_time};
+use notify::{create_notifier, WakeupQueue};
+use timer::TimerHandle;
+use tokens::{self, Source};
 use {Notifier, Time};
 use {Evented, EventSet, PollOpt, Timeout, TimerError};
 
@@ -33,10 +38,14 @@ use {Evented, EventSet, PollOpt, Timeout, TimerError};
 /// The structure derefs to the context (``C``) for convenience
 pub struct Scope<'a, C:Sized+'a>{
     token: Token,
+    /// The token used to register I/O objects, see `tokens` module
+    io_token: Token,
     ctx: &'a mut C,
     channel: &'a mut Sender<Notify>,
+    queue: Option<&'a Arc<WakeupQueue>>,
     loop_api: &'a mut LoopApi,
     time: Time,
+    machines: Option<&'a Introspect>,
 }
 
 /// This is a structure that works similarly to Scope, but doesn't
@@ -48,6 +57,7 @@ pub struct Scope<'a, C:Sized+'a>{
 pub struct EarlyScope<'a> {
     token: Token,
     channel: &'a mut Sender<Notify>,
+    queue: Option<&'a Arc<WakeupQueue>>,
     loop_api: &'a mut LoopApi,
 }
 
@@ -95,21 +105,56 @@ pub trait GenericScope {
     fn estimate_system_time(&self, time: Time) -> SystemTime {
         estimate_system_time(self.now(), time)
     }
+
+    /// Returns the Time in this loop that corresponds to the SystemTime
+    ///
+    /// This is an estimate too (see `estimate_system_time`). The deadline
+    /// made of it is not adjusted when the system clock changes, use
+    /// `WallDeadline` for that.
+    fn estimate_time(&self, time: SystemTime) -> Time {
+        estimate_time(self.now(), time)
+    }
+}
+
+/// More methods common for `Scope` and `EarlyScope`
+///
+/// They are in a separate trait, so implementations of `GenericScope`
+/// outside of this crate don't need to provide them.
+pub trait GenericScopeExt: GenericScope {
+    /// Returns the token of the enclosed state machine
+    fn token(&self) -> Token;
+    /// Register an I/O object with the source id
+    ///
+    /// The id is passed to `Machine::ready_source` when the object is ready
+    fn register_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>;
+    fn reregister_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>;
 }
 
 impl<'a, C:Sized+'a> Scope<'a, C> {
 
+    /// Register an I/O object for the enclosed state machine
+    ///
+    /// Events of the objects which are left registered after the state
+    /// machine exits (e.g. shared or duplicated file descriptors) are not
+    /// delivered to the state machine which reuses the token. Still, such
+    /// objects should be deregistered explicitly, because level-triggered
+    /// ones keep waking up the loop. Use `register_tracked` to make the
+    /// loop do that.
     pub fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt)
         -> io::Result<()>
     {
-        self.loop_api.register(io, self.token, interest, opt)
+        self.loop_api.register(io, self.io_token, interest, opt)
     }
 
     pub fn reregister(&mut self, io: &Evented,
         interest: EventSet, opt: PollOpt)
         -> io::Result<()>
     {
-        self.loop_api.reregister(io, self.token, interest, opt)
+        self.loop_api.reregister(io, self.io_token, interest, opt)
     }
 
     pub fn deregister(&mut self, io: &Evented) -> io::Result<()>
@@ -117,6 +162,64 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
         self.loop_api.deregister(io)
     }
 
+    /// Register an I/O object and deregister it when the state machine exits
+    ///
+    /// Works like `register`, but the loop remembers the file descriptor
+    /// and deregisters it if it's still registered when the enclosed state
+    /// machine exits (or on `deregister_all`). If the object is registered
+    /// with `register_tracked` by another state machine, it's deregistered
+    /// when that one exits instead.
+    #[cfg(unix)]
+    pub fn register_tracked<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.register(io, self.io_token, interest, opt));
+        self.loop_api.track(self.token, io.as_raw_fd());
+        Ok(())
+    }
+
+    /// Deregister an object registered with `register_tracked`
+    #[cfg(unix)]
+    pub fn deregister_tracked<E>(&mut self, io: &E) -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        self.loop_api.untrack(io.as_raw_fd());
+        self.loop_api.deregister(io)
+    }
+
+    /// Deregister all I/O objects registered with `register_tracked`
+    ///
+    /// This is done by the loop when the state machine exits. Use it when
+    /// the state machine is replaced by another one in place (e.g. by a
+    /// supervisor), so the new one doesn't receive events of the objects
+    /// left by the old one.
+    pub fn deregister_all(&mut self) {
+        self.loop_api.release(self.token)
+    }
+
+    /// Register an I/O object with the source id
+    ///
+    /// Use this when the state machine has several I/O objects. The id is
+    /// passed to `Machine::ready_source` when the object is ready. Objects
+    /// registered with `register` have `Source::default()` (i.e. zero).
+    pub fn register_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let token = tokens::with_source(self.io_token, source);
+        self.loop_api.register(io, token, interest, opt)
+    }
+
+    pub fn reregister_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let token = tokens::with_source(self.io_token, source);
+        self.loop_api.reregister(io, token, interest, opt)
+    }
+
     /// Add timeout
     ///
     /// This method is **deprecated** use return value of your state machine's
@@ -137,7 +240,33 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
 
     /// Create a `Notifier` that may be used to `wakeup` enclosed state machine
     pub fn notifier(&self) -> Notifier {
-        create_notifier(self.token, self.channel)
+        create_notifier(self.io_token, self.channel, self.queue)
+    }
+
+    /// Returns the token of the enclosed state machine
+    ///
+    /// Useful for logging and for correlating with `Notifier` objects. Note
+    /// that tokens are reused quickly after the state machine exits.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Lists other state machines in the loop
+    ///
+    /// The enclosed state machine itself is not listed, because it's
+    /// detached from the loop while its action is running. The list is
+    /// empty when called from `Machine::create` or when adding a state
+    /// machine with `add_machine_with`.
+    pub fn machines(&self) -> Vec<MachineInfo> {
+        self.machines.map(|m| m.machines()).unwrap_or_else(Vec::new)
+    }
+
+    /// Returns the number of actions dispatched so far
+    ///
+    /// Zero counters are returned in the same cases where `machines()`
+    /// returns an empty list.
+    pub fn counters(&self) -> Counters {
+        self.machines.map(|m| m.counters()).unwrap_or_default()
     }
 
     /// Shutdown the event loop
@@ -163,6 +292,32 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
     pub fn estimate_system_time(&self, time: Time) -> SystemTime {
         estimate_system_time(self.now(), time)
     }
+
+    /// Returns a scope which has only a part of the context
+    ///
+    /// The resulting scope refers to the same state machine, so it may be
+    /// passed to the action handler of a child machine which is written
+    /// for its own (smaller) context type. For example:
+    ///
+    /// ```ignore
+    /// http_machine.ready(events, &mut scope.project(|ctx| &mut ctx.http))
+    /// ```
+    ///
+    /// See also `Mount` which does this for every action of a machine.
+    pub fn project<'b, D, F>(&'b mut self, f: F) -> Scope<'b, D>
+        where F: FnOnce(&mut C) -> &mut D
+    {
+        Scope {
+            token: self.token,
+            io_token: self.io_token,
+            ctx: f(&mut *self.ctx),
+            channel: &mut *self.channel,
+            queue: self.queue,
+            loop_api: &mut *self.loop_api,
+            time: self.time,
+            machines: self.machines,
+        }
+    }
 }
 
 impl<'a, C:Sized+'a> GenericScope for Scope<'a, C> {
@@ -216,6 +371,26 @@ impl<'a, C:Sized+'a> GenericScope for Scope<'a, C> {
     }
 }
 
+impl<'a, C:Sized+'a> GenericScopeExt for Scope<'a, C> {
+    fn token(&self) -> Token {
+        self.token
+    }
+
+    fn register_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.register_source(io, source, interest, opt)
+    }
+
+    fn reregister_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.reregister_source(io, source, interest, opt)
+    }
+}
+
 impl<'a, C> Deref for Scope<'a, C> {
     type Target = C;
     fn deref(&self) -> &C {
@@ -249,6 +424,51 @@ impl<'a> EarlyScope<'a> {
         self.loop_api.deregister(io)
     }
 
+    /// Register an I/O object and deregister it when the state machine exits
+    ///
+    /// See `Scope::register_tracked`
+    #[cfg(unix)]
+    pub fn register_tracked<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.register(io, self.token, interest, opt));
+        self.loop_api.track(self.token, io.as_raw_fd());
+        Ok(())
+    }
+
+    /// Deregister an object registered with `register_tracked`
+    #[cfg(unix)]
+    pub fn deregister_tracked<E>(&mut self, io: &E) -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        self.loop_api.untrack(io.as_raw_fd());
+        self.loop_api.deregister(io)
+    }
+
+    /// Deregister all I/O objects registered with `register_tracked`
+    pub fn deregister_all(&mut self) {
+        self.loop_api.release(self.token)
+    }
+
+    /// Register an I/O object with the source id
+    pub fn register_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let token = tokens::with_source(self.token, source);
+        self.loop_api.register(io, token, interest, opt)
+    }
+
+    pub fn reregister_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let token = tokens::with_source(self.token, source);
+        self.loop_api.reregister(io, token, interest, opt)
+    }
+
     /// Add timeout
     ///
     /// This method is **deprecated** use return value of your state machine's
@@ -269,7 +489,12 @@ impl<'a> EarlyScope<'a> {
 
     /// Create a `Notifier` that may be used to `wakeup` enclosed state machine
     pub fn notifier(&self) -> Notifier {
-        create_notifier(self.token, self.channel)
+        create_notifier(self.token, self.channel, self.queue)
+    }
+
+    /// Returns the token of the enclosed state machine
+    pub fn token(&self) -> Token {
+        self.token
     }
 
     /// Time of the current loop iteration
@@ -336,6 +561,7 @@ impl<'a> GenericScope for EarlyScope<'a> {
     fn notifier(&self) -> Notifier {
         self.notifier()
     }
+
     /// Time of the current loop iteration
     ///
     /// This is a time that needs to be used for timeouts. It's cheap to use
@@ -346,6 +572,55 @@ impl<'a> GenericScope for EarlyScope<'a> {
     }
 }
 
+impl<'a> GenericScopeExt for EarlyScope<'a> {
+    fn token(&self) -> Token {
+        self.token
+    }
+
+    fn register_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.register_source(io, source, interest, opt)
+    }
+
+    fn reregister_source(&mut self, io: &Evented, source: Source,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.reregister_source(io, source, interest, opt)
+    }
+}
+
+/// A scope which is able to set the deadline of the enclosed state machine
+pub trait DeadlineScope: GenericScope {
+    fn set_deadline(&mut self, deadline: Time)
+        -> Result<TimerHandle, TimerError>;
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool;
+}
+
+impl<'a, C:Sized+'a> DeadlineScope for Scope<'a, C> {
+    fn set_deadline(&mut self, deadline: Time)
+        -> Result<TimerHandle, TimerError>
+    {
+        self.loop_api.set_deadline(self.token, self.time, deadline)
+    }
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool {
+        self.loop_api.clear_deadline(handle)
+    }
+}
+
+impl<'a> DeadlineScope for EarlyScope<'a> {
+    fn set_deadline(&mut self, deadline: Time)
+        -> Result<TimerHandle, TimerError>
+    {
+        self.loop_api.set_deadline(self.token, Time::zero(), deadline)
+    }
+    fn clear_deadline(&mut self, handle: TimerHandle) -> bool {
+        self.loop_api.clear_deadline(handle)
+    }
+}
+
 #[doc(hidden)]
 pub fn scope<'x, C, L:LoopApi>(time: Time, token: Token, ctx: &'x mut C,
     channel: &'x mut Sender<Notify>, loop_api: &'x mut L)
@@ -353,10 +628,52 @@ pub fn scope<'x, C, L:LoopApi>(time: Time, token: Token, ctx: &'x mut C,
 {
     Scope {
         token: token,
+        io_token: token,
         ctx: ctx,
         channel: channel,
+        queue: None,
         loop_api: loop_api,
         time: time,
+        machines: None,
+    }
+}
+
+/// Creates a scope for the state machine with the serial number
+///
+/// I/O objects are registered with the token tagged by the serial
+pub fn serial_scope<'x, C, L:LoopApi>(time: Time, token: Token, serial: u64,
+    ctx: &'x mut C, channel: &'x mut Sender<Notify>,
+    queue: &'x Arc<WakeupQueue>, loop_api: &'x mut L)
+    -> Scope<'x, C>
+{
+    Scope {
+        token: token,
+        io_token: tokens::encode(token, serial),
+        ctx: ctx,
+        channel: channel,
+        queue: Some(queue),
+        loop_api: loop_api,
+        time: time,
+        machines: None,
+    }
+}
+
+/// Creates a scope which is able to list other state machines
+pub fn machine_scope<'x, C, L:LoopApi>(time: Time, token: Token,
+    serial: u64, ctx: &'x mut C, channel: &'x mut Sender<Notify>,
+    queue: &'x Arc<WakeupQueue>, loop_api: &'x mut L,
+    machines: &'x Introspect)
+    -> Scope<'x, C>
+{
+    Scope {
+        token: token,
+        io_token: tokens::encode(token, serial),
+        ctx: ctx,
+        channel: channel,
+        queue: Some(queue),
+        loop_api: loop_api,
+        time: time,
+        machines: Some(machines),
     }
 }
 
@@ -368,6 +685,21 @@ pub fn early_scope<'x, L:LoopApi>(token: Token,
     EarlyScope {
         token: token,
         channel: channel,
+        queue: None,
+        loop_api: loop_api,
+    }
+}
+
+/// Creates an early scope which wakes up state machines via the queue
+pub fn queued_early_scope<'x, L:LoopApi>(token: Token,
+    channel: &'x mut Sender<Notify>, queue: &'x Arc<WakeupQueue>,
+    loop_api: &'x mut L)
+    -> EarlyScope<'x>
+{
+    EarlyScope {
+        token: token,
+        channel: channel,
+        queue: Some(queue),
         loop_api: loop_api,
     }
 }
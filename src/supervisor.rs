@@ -0,0 +1,503 @@
+use std::error::Error;
+use std::any::type_name;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use mio::Token;
+use void::Void;
+
+use response::ResponseImpl;
+use {Machine, Scope, Response, EventSet, SpawnError, Time, GenericScope};
+use Source;
+use Exit;
+
+
+/// A restart strategy of the `Supervisor`
+///
+/// The supervised state machine is restarted when it exits with an error.
+/// Restarts are delayed with exponential backoff: the first restart is
+/// delayed by the minimum backoff, every next one by twice as much, but
+/// not more than the maximum backoff. Only restarts in the last `window`
+/// count, so the delay decreases when the machine works stable for a while.
+///
+/// When there are more than `max_restarts` in the window, the supervisor
+/// gives up and exits with an error.
+///
+/// Setters return the strategy, so they may be chained:
+///
+/// ```ignore
+/// let mut strategy = Strategy::new();
+/// strategy.max_restarts(10, Duration::from_secs(60))
+///     .backoff(Duration::from_millis(10), Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Strategy {
+    max_restarts: usize,
+    window: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    restart_on_done: bool,
+}
+
+impl Strategy {
+    /// Creates a strategy with default options
+    ///
+    /// Defaults are: at most 5 restarts in 60 seconds, backoff from
+    /// 100 milliseconds to 10 seconds, don't restart on `Response::done()`
+    pub fn new() -> Strategy {
+        Strategy {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            restart_on_done: false,
+        }
+    }
+    /// Maximum number of restarts in a time window
+    pub fn max_restarts(&mut self, restarts: usize, window: Duration)
+        -> &mut Strategy
+    {
+        self.max_restarts = restarts;
+        self.window = window;
+        self
+    }
+    /// Minimum and maximum delay before restart
+    ///
+    /// The delay never exceeds the maximum, even if the minimum is larger.
+    pub fn backoff(&mut self, min: Duration, max: Duration) -> &mut Strategy {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+    /// Restart the state machine when it exits with `Response::done()` too
+    ///
+    /// This is useful for connections to upstreams which should always be
+    /// there. Disabled by default.
+    pub fn restart_on_done(&mut self, enable: bool) -> &mut Strategy {
+        self.restart_on_done = enable;
+        self
+    }
+    fn delay(&self, restarts: usize) -> Duration {
+        let mut delay = self.min_backoff;
+        for _ in 0..restarts {
+            if delay >= self.max_backoff {
+                break;
+            }
+            delay = delay.checked_mul(2).unwrap_or(self.max_backoff);
+        }
+        if delay > self.max_backoff {
+            self.max_backoff
+        } else {
+            delay
+        }
+    }
+}
+
+struct Restart<M: Machine> {
+    factory: Box<FnMut() -> M::Seed>,
+    strategy: Strategy,
+    restarts: VecDeque<Time>,
+}
+
+enum State<M> {
+    Running(M),
+    Waiting(Time),
+}
+
+/// Restarts the state machine when it fails
+///
+/// The supervisor owns a seed factory, every time the machine needs to be
+/// (re)started, a new seed is created by the factory and passed to the
+/// `Machine::create`. The restarts are done according to the `Strategy`.
+/// The wrapped machine is restarted in place (i.e. keeps the token), so
+/// every supervisor restarts exactly one machine (one-for-one). The I/O
+/// objects which the failed machine has registered with
+/// `Scope::register_tracked` are deregistered before the restart.
+///
+/// ```ignore
+/// let mut strategy = Strategy::new();
+/// strategy.max_restarts(10, Duration::from_secs(60));
+/// loop_inst.add_machine_with(|scope| {
+///     Supervisor::new(move || upstream_addr, strategy, scope)
+/// }).unwrap();
+/// ```
+///
+/// The children spawned by the supervised state machine are not supervised.
+/// Panics are not restarted unless converted to errors, e.g. by the
+/// `CatchPanic` layer.
+pub struct Supervisor<M: Machine> {
+    state: State<M>,
+    restart: Option<Restart<M>>,
+}
+
+impl<M: Machine> Supervisor<M> {
+    /// Creates a supervisor
+    ///
+    /// The state machine is created on the first iteration of the loop
+    pub fn new<F, S>(factory: F, strategy: Strategy, scope: &mut S)
+        -> Response<Supervisor<M>, Void>
+        where F: FnMut() -> M::Seed + 'static,
+              S: GenericScope,
+    {
+        let now = scope.now();
+        Response::ok(Supervisor {
+            state: State::Waiting(now),
+            restart: Some(Restart {
+                factory: Box::new(factory),
+                strategy: strategy,
+                restarts: VecDeque::new(),
+            }),
+        }).deadline(now)
+    }
+    /// Returns a reference to the state machine if it's running
+    pub fn get_ref(&self) -> Option<&M> {
+        match self.state {
+            State::Running(ref m) => Some(m),
+            State::Waiting(_) => None,
+        }
+    }
+    fn waiting(self) -> Response<Self, M::Seed> {
+        match self.state {
+            State::Waiting(time) => Response::ok(self).deadline(time),
+            State::Running(_) => unreachable!(),
+        }
+    }
+}
+
+impl<M: Machine> Restart<M> {
+    fn failed<N>(mut self, now: Time, err: Box<Error>)
+        -> Response<Supervisor<M>, N>
+    {
+        let window = self.strategy.window;
+        self.restarts.retain(|&time| time.saturating_add(window) > now);
+        if self.restarts.len() >= self.strategy.max_restarts {
+            error!("Supervised state machine failed: {}. Too many restarts \
+                ({} in {:?}), giving up", err, self.restarts.len(), window);
+            return Response::error(format!("too many restarts, \
+                last error: {}", err).into());
+        }
+        let delay = self.strategy.delay(self.restarts.len());
+        warn!("Supervised state machine failed: {}. Restarting in {:?}",
+            err, delay);
+        self.restarts.push_back(now);
+        let restart = now.saturating_add(delay);
+        Response::ok(Supervisor {
+            state: State::Waiting(restart),
+            restart: Some(self),
+        }).deadline(restart)
+    }
+}
+
+fn supervise<M: Machine>(restart: Option<Restart<M>>,
+    response: Response<M, M::Seed>, scope: &mut Scope<M::Context>)
+    -> Response<Supervisor<M>, M::Seed>
+{
+    let restart = match restart {
+        Some(restart) => restart,
+        None => return response.wrap(|m| Supervisor {
+            state: State::Running(m),
+            restart: None,
+        }),
+    };
+    let err = match response.0 {
+        ResponseImpl::Error(e) => e,
+        ResponseImpl::Done if restart.strategy.restart_on_done => {
+            "state machine exited".into()
+        }
+        ResponseImpl::Done => return Response::done(),
+        other => return Response(other).wrap(|m| Supervisor {
+            state: State::Running(m),
+            restart: Some(restart),
+        }),
+    };
+    // The token is kept by the new instance, so it must not receive events
+    // of the objects left by the failed one
+    scope.deregister_all();
+    restart.failed(scope.now(), err)
+}
+
+impl<M: Machine> Machine for Supervisor<M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        supervise(None, M::create(seed, scope), scope)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.ready(events, scope), scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn ready_source(self, events: EventSet, source: Source,
+                    scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.ready_source(events, source, scope),
+                          scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.spawned(scope), scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.spawn_error(scope, error), scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn child_exited(self, scope: &mut Scope<M::Context>,
+                    child: Token, reason: Exit)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.child_exited(scope, child, reason),
+                          scope)
+            }
+            // A child of the previous instance of the state machine
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let now = scope.now();
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.timeout(scope), scope)
+            }
+            State::Waiting(time) if time <= now => {
+                let mut restart = self.restart
+                    .expect("only supervisor with factory may wait");
+                let seed = (restart.factory)();
+                supervise(Some(restart), M::create(seed, scope), scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+    fn describe(&self) -> Option<String> {
+        match self.state {
+            State::Running(ref m) => m.describe(),
+            State::Waiting(time) => {
+                Some(format!("waiting for restart until {:?}", time))
+            }
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self.state {
+            State::Running(ref m) => m.name(),
+            State::Waiting(_) => type_name::<M>(),
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.state {
+            State::Running(m) => {
+                supervise(self.restart, m.wakeup(scope), scope)
+            }
+            State::Waiting(_) => self.waiting(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use mio::{Token, PollOpt};
+    use mio::deprecated::unix::{pipe, PipeReader};
+
+    use response::decompose;
+    use test_util::with_scope;
+    use {Machine, Scope, Response, EventSet, Loop, Config, Time};
+    use super::{Supervisor, Strategy};
+
+    struct Context {
+        starts: Vec<Time>,
+        reader: Option<PipeReader>,
+    }
+
+    struct Flaky;
+
+    impl Machine for Flaky {
+        type Context = Context;
+        type Seed = ();
+        fn create(_seed: (), scope: &mut Scope<Context>)
+            -> Response<Self, ()>
+        {
+            let now = scope.now();
+            scope.starts.push(now);
+            if let Some(reader) = scope.reader.take() {
+                // Fails if the previous instance is still registered
+                scope.register_tracked(&reader, EventSet::readable(),
+                                       PollOpt::level()).unwrap();
+                scope.reader = Some(reader);
+                return Response::ok(Flaky);
+            }
+            Response::ok(Flaky).deadline(now)
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Context>)
+            -> Response<Self, ()>
+        {
+            Response::error("connection reset".into())
+        }
+        fn spawned(self, _scope: &mut Scope<Context>)
+            -> Response<Self, ()>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _scope: &mut Scope<Context>)
+            -> Response<Self, ()>
+        {
+            Response::error("connection reset".into())
+        }
+        fn wakeup(self, _scope: &mut Scope<Context>)
+            -> Response<Self, ()>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let mut strategy = Strategy::new();
+        strategy.backoff(Duration::from_millis(10), Duration::from_millis(25));
+        assert_eq!(strategy.delay(0), Duration::from_millis(10));
+        assert_eq!(strategy.delay(1), Duration::from_millis(20));
+        assert_eq!(strategy.delay(2), Duration::from_millis(25));
+        assert_eq!(strategy.delay(100), Duration::from_millis(25));
+    }
+
+    #[test]
+    fn huge_durations() {
+        let forever = Duration::new(u64::max_value(), 999_999_999);
+        let half = Duration::new(u64::max_value() / 2 + 1, 0);
+        let mut strategy = Strategy::new();
+        strategy.max_restarts(2, forever).backoff(half, forever);
+        assert_eq!(strategy.delay(0), half);
+        assert_eq!(strategy.delay(1), forever);
+        assert_eq!(strategy.delay(100), forever);
+
+        let mut ctx = Context { starts: Vec::new(), reader: None };
+        let now = Time::zero() + Duration::from_secs(10);
+        let (sup, _, _) = with_scope(now, &mut ctx, |scope| {
+            decompose(Token(0),
+                Supervisor::<Flaky>::new(|| (), strategy, scope))
+        });
+        let (started, _, _) = with_scope(now, &mut ctx, |scope| {
+            decompose(Token(0), sup.ok().unwrap().timeout(scope))
+        });
+        let (failed, _, deadline) = with_scope(now, &mut ctx, |scope| {
+            decompose(Token(0), started.ok().unwrap().timeout(scope))
+        });
+        let restart = now.saturating_add(forever);
+        assert_eq!(deadline, Some(restart));
+        // the window of the first restart ends past the end of times
+        let (started, _, _) = with_scope(restart, &mut ctx, |scope| {
+            decompose(Token(0), failed.ok().unwrap().timeout(scope))
+        });
+        let (failed, _, deadline) = with_scope(restart, &mut ctx, |scope| {
+            decompose(Token(0), started.ok().unwrap().timeout(scope))
+        });
+        assert!(failed.is_ok());
+        assert_eq!(deadline, Some(restart));
+        assert_eq!(ctx.starts, vec![now, restart]);
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut strategy = Strategy::new();
+        strategy.max_restarts(3, Duration::from_secs(60));
+        strategy.backoff(Duration::from_millis(5), Duration::from_millis(20));
+        let mut ctx = Context { starts: Vec::new(), reader: None };
+        let start = Time::zero();
+        let (sup, _, deadline) = with_scope(start, &mut ctx, |scope| {
+            decompose(Token(0),
+                Supervisor::<Flaky>::new(|| (), strategy, scope))
+        });
+        assert_eq!(deadline, Some(start));
+        let mut sup = sup.ok().unwrap();
+        let mut now = start;
+        for &delay in &[5, 10, 20] {
+            let (started, _, deadline) = with_scope(now, &mut ctx, |scope| {
+                decompose(Token(0), sup.timeout(scope))
+            });
+            assert_eq!(deadline, Some(now));
+            let (failed, _, deadline) = with_scope(now, &mut ctx, |scope| {
+                decompose(Token(0), started.ok().unwrap().timeout(scope))
+            });
+            let restart = now + Duration::from_millis(delay);
+            assert_eq!(deadline, Some(restart));
+            // spurious timeout doesn't restart the machine early
+            let (waiting, _, deadline) = with_scope(now, &mut ctx, |scope| {
+                decompose(Token(0), failed.ok().unwrap().timeout(scope))
+            });
+            assert_eq!(deadline, Some(restart));
+            sup = waiting.ok().unwrap();
+            now = restart;
+        }
+        let (started, _, _) = with_scope(now, &mut ctx, |scope| {
+            decompose(Token(0), sup.timeout(scope))
+        });
+        let (failed, _, _) = with_scope(now, &mut ctx, |scope| {
+            decompose(Token(0), started.ok().unwrap().timeout(scope))
+        });
+        assert_eq!(failed.err().unwrap().unwrap().to_string(),
+                   "too many restarts, last error: connection reset");
+        let ms = |ms| start + Duration::from_millis(ms);
+        assert_eq!(ctx.starts, vec![ms(0), ms(5), ms(15), ms(35)]);
+    }
+
+    #[test]
+    fn deregister_on_restart() {
+        let mut strategy = Strategy::new();
+        strategy.max_restarts(2, Duration::from_secs(60));
+        strategy.backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let (reader, mut writer) = pipe().unwrap();
+        // The reader is always readable, so every instance fails at once
+        writer.write_all(b"x").unwrap();
+        let exited = Rc::new(Cell::new(false));
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        let flag = exited.clone();
+        lc.on_exit(move |ctx: &mut Context, _, _, reason| {
+            // first start and two restarts
+            assert_eq!(ctx.starts.len(), 3);
+            assert_eq!(reason.to_string(), "error: too many restarts, \
+                last error: connection reset");
+            flag.set(true);
+        });
+        lc.add_machine_with(|scope| {
+            Supervisor::<Flaky>::new(|| (), strategy, scope)
+        }).unwrap();
+        // Flaky panics if the reader is not deregistered after the failure
+        lc.run(Context { starts: Vec::new(), reader: Some(reader) }).unwrap();
+        assert!(exited.get());
+    }
+}
@@ -0,0 +1,30 @@
+//! Fixtures shared by the unit tests
+use mio::Token;
+use mio::deprecated::EventLoop;
+
+use handler::Handler;
+use scope::scope;
+use {Periodic, Scope, Time};
+
+
+/// Any machine will do, the loop is only used for the channel and the
+/// registrations
+pub type Dummy = Periodic<(), fn(&mut Scope<()>) -> bool>;
+
+/// Creates an event loop which is never run
+pub fn event_loop() -> EventLoop<Handler<Dummy>> {
+    EventLoop::new().unwrap()
+}
+
+/// Calls `f` with the scope of `Token(0)` at the `time`
+///
+/// The scope is not attached to a running loop, so actions of the state
+/// machines may be called directly.
+pub fn with_scope<C, R, F>(time: Time, ctx: &mut C, f: F) -> R
+    where F: FnOnce(&mut Scope<C>) -> R
+{
+    let mut eloop = event_loop();
+    let mut channel = eloop.channel();
+    let ref mut scope = scope(time, Token(0), ctx, &mut channel, &mut eloop);
+    f(scope)
+}
@@ -0,0 +1,279 @@
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use void::{Void, unreachable};
+
+use {Machine, Scope, Response, EventSet, Time};
+
+
+/// What to do when the loop was late for one or more ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Fire once and continue with the next tick in the future
+    Skip,
+    /// Fire once for every missed tick
+    Burst,
+}
+
+/// A schedule of periodic ticks
+///
+/// The next tick is scheduled from the previous deadline, not from the
+/// time the tick was processed, so ticks don't drift away when the loop is
+/// busy. The jitter (if any) is added to every deadline separately, so it
+/// doesn't accumulate either.
+///
+/// The ticker is a building block for state machines that do something
+/// periodically besides other things:
+///
+/// ```ignore
+/// fn timeout(mut self, scope: &mut Scope<Context>) -> Response<Self, Void> {
+///     for _ in 0..self.ticker.tick(scope.now()) {
+///         self.ping(scope);
+///     }
+///     let deadline = self.ticker.deadline();
+///     Response::ok(self).deadline(deadline)
+/// }
+/// ```
+///
+/// For the state machine that does nothing else, use `Periodic`.
+#[derive(Debug, Clone)]
+pub struct Ticker {
+    interval: Duration,
+    jitter: Duration,
+    missed: MissedTicks,
+    /// The scheduled time of the next tick
+    next: Time,
+    /// The jitter of the next tick
+    offset: Duration,
+    /// State of the random number generator for the jitter
+    random: u64,
+}
+
+fn nanos(dur: Duration) -> u64 {
+    dur.as_secs() * 1_000_000_000 + dur.subsec_nanos() as u64
+}
+
+impl Ticker {
+    /// Creates a ticker, the first tick is `interval` after `now`
+    ///
+    /// # Panics
+    ///
+    /// When the interval is zero
+    pub fn new(interval: Duration, now: Time) -> Ticker {
+        assert!(interval > Duration::new(0, 0), "interval must be positive");
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64).unwrap_or(0);
+        Ticker {
+            interval: interval,
+            jitter: Duration::new(0, 0),
+            missed: MissedTicks::Skip,
+            next: now + interval,
+            offset: Duration::new(0, 0),
+            // xorshift must not be seeded with zero
+            random: seed | 1,
+        }
+    }
+    /// Delays every tick by a random duration up to `max`
+    ///
+    /// Useful to spread the load when many machines tick with the same
+    /// interval. Zero (the default) disables jitter. The jitter must be
+    /// shorter than the interval, so that the ticks keep their order.
+    ///
+    /// # Panics
+    ///
+    /// When `max` is not less than the interval
+    pub fn jitter(&mut self, max: Duration) {
+        assert!(max < self.interval, "jitter must be less than interval");
+        self.jitter = max;
+        self.offset = self.random_offset();
+    }
+    /// Sets the policy for missed ticks, `Skip` by default
+    pub fn missed_ticks(&mut self, policy: MissedTicks) {
+        self.missed = policy;
+    }
+    fn random_offset(&mut self) -> Duration {
+        let max = nanos(self.jitter);
+        if max == 0 {
+            return Duration::new(0, 0);
+        }
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        let value = self.random % (max + 1);
+        Duration::new(value / 1_000_000_000, (value % 1_000_000_000) as u32)
+    }
+    /// Returns the deadline of the next tick (including the jitter)
+    pub fn deadline(&self) -> Time {
+        self.next + self.offset
+    }
+    /// Returns `true` if the next tick is due
+    pub fn is_due(&self, now: Time) -> bool {
+        self.deadline() <= now
+    }
+    /// Advances the ticker and returns the number of ticks to fire
+    ///
+    /// Returns zero when the tick is not due yet (e.g. the state machine
+    /// was woken up for another reason). Otherwise returns one, or the
+    /// number of ticks since the last call with `MissedTicks::Burst`.
+    pub fn tick(&mut self, now: Time) -> u64 {
+        if !self.is_due(now) {
+            return 0;
+        }
+        let mut missed = 0;
+        while self.next <= now {
+            self.next = self.next + self.interval;
+            missed += 1;
+        }
+        self.offset = self.random_offset();
+        match self.missed {
+            MissedTicks::Skip => 1,
+            MissedTicks::Burst => missed,
+        }
+    }
+}
+
+/// A state machine which calls the function periodically
+///
+/// The function is called for every tick of the `Ticker`. It returns
+/// `false` to stop the state machine.
+///
+/// ```ignore
+/// let ticker = Ticker::new(Duration::new(10, 0), scope.now());
+/// Periodic::new(ticker, |scope: &mut Scope<Context>| {
+///     scope.stats.flush();
+///     true
+/// }, scope)
+/// ```
+pub struct Periodic<C, F> {
+    ticker: Ticker,
+    callback: F,
+    phantom: PhantomData<*const C>,
+}
+
+impl<C, F> Periodic<C, F>
+    where F: FnMut(&mut Scope<C>) -> bool
+{
+    pub fn new<N>(ticker: Ticker, callback: F, _scope: &mut Scope<C>)
+        -> Response<Periodic<C, F>, N>
+    {
+        let deadline = ticker.deadline();
+        Response::ok(Periodic {
+            ticker: ticker,
+            callback: callback,
+            phantom: PhantomData,
+        }).deadline(deadline)
+    }
+    fn schedule<N>(self) -> Response<Self, N> {
+        let deadline = self.ticker.deadline();
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+impl<C, F> Machine for Periodic<C, F>
+    where F: FnMut(&mut Scope<C>) -> bool
+{
+    type Context = C;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, _scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        self.schedule()
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.schedule()
+    }
+    fn timeout(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        for _ in 0..self.ticker.tick(scope.now()) {
+            if !(self.callback)(scope) {
+                return Response::done();
+            }
+        }
+        self.schedule()
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.schedule()
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("periodic, every {:?}", self.ticker.interval))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use {Time, Loop, Config, Scope};
+    use super::{Ticker, MissedTicks, Periodic};
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    #[test]
+    fn drift_and_missed_ticks() {
+        let start = Time::zero();
+        let mut ticker = Ticker::new(ms(100), start);
+        assert_eq!(ticker.deadline(), start + ms(100));
+        assert_eq!(ticker.tick(start + ms(50)), 0);
+        // late tick doesn't shift the schedule
+        assert_eq!(ticker.tick(start + ms(130)), 1);
+        assert_eq!(ticker.deadline(), start + ms(200));
+        // missed ticks are skipped
+        assert_eq!(ticker.tick(start + ms(450)), 1);
+        assert_eq!(ticker.deadline(), start + ms(500));
+        ticker.missed_ticks(MissedTicks::Burst);
+        assert_eq!(ticker.tick(start + ms(820)), 4);
+        assert_eq!(ticker.deadline(), start + ms(900));
+    }
+
+    #[test]
+    fn jitter() {
+        let start = Time::zero();
+        let mut ticker = Ticker::new(ms(100), start);
+        ticker.jitter(ms(20));
+        for i in 1..50 {
+            let deadline = ticker.deadline();
+            assert!(deadline >= start + ms(100 * i));
+            assert!(deadline <= start + ms(100 * i + 20));
+            assert_eq!(ticker.tick(deadline), 1);
+        }
+        ticker.jitter(ms(99));
+        ticker.jitter(ms(0));
+        assert_eq!(ticker.deadline(), start + ms(5000));
+    }
+
+    #[test]
+    #[should_panic(expected = "jitter must be less than interval")]
+    fn jitter_longer_than_interval() {
+        let mut ticker = Ticker::new(ms(100), Time::zero());
+        ticker.jitter(ms(100));
+    }
+
+    type Counter = Rc<Cell<u32>>;
+
+    fn count(scope: &mut Scope<Counter>) -> bool {
+        scope.set(scope.get() + 1);
+        scope.get() < 3
+    }
+
+    #[test]
+    fn periodic() {
+        let counter = Rc::new(Cell::new(0));
+        let lc = Loop::<Periodic<Counter, fn(&mut Scope<Counter>) -> bool>>
+            ::new(&Config::new()).unwrap();
+        let mut inst = lc.instantiate(counter.clone());
+        inst.add_machine_with(|scope| {
+            let ticker = Ticker::new(ms(1), scope.now());
+            Periodic::new(ticker, count as fn(&mut Scope<Counter>) -> bool,
+                          scope)
+        }).unwrap();
+        inst.run().unwrap();
+        assert_eq!(counter.get(), 3);
+    }
+}
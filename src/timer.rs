@@ -0,0 +1,292 @@
+//! Timers of the state machine deadlines
+//!
+//! By default deadlines are served by the timer of the mio's event loop,
+//! which has a granularity of the timer tick (100 ms by default) and a
+//! millisecond precision at best.
+//!
+//! With the `precise_time` feature deadlines are kept in the ordered map,
+//! and a single `timerfd` is armed for the earliest one. Expired deadlines
+//! are dispatched when the timerfd becomes readable.
+#[cfg(feature="precise_time")]
+use mio::Token;
+
+pub use self::imp::{Timers, TimerHandle};
+
+#[cfg(all(feature="precise_time", not(target_os="linux")))]
+compile_error!("the `precise_time` feature is only supported on linux");
+
+
+#[cfg(not(feature="precise_time"))]
+mod imp {
+    use std::io;
+    use std::time::Duration;
+
+    use mio::Token;
+    use mio::deprecated::{EventLoop, Handler as MioHandler};
+
+    use handler::Timeo;
+    use loop_time::mio_timeout_ms;
+    use {Time, Timeout, TimerError};
+
+    pub type TimerHandle = Timeout;
+
+    /// Deadlines are put directly into the mio's timer
+    pub struct Timers;
+
+    impl Timers {
+        pub fn new() -> io::Result<Timers> {
+            Ok(Timers)
+        }
+        pub fn register<H>(&self, _eloop: &mut EventLoop<H>)
+            -> io::Result<()>
+            where H: MioHandler
+        {
+            Ok(())
+        }
+        pub fn set<H>(&mut self, eloop: &mut EventLoop<H>, token: Token,
+            now: Time, deadline: Time)
+            -> Result<TimerHandle, TimerError>
+            where H: MioHandler<Timeout=Timeo>
+        {
+            let ms = mio_timeout_ms(now, deadline);
+            eloop.timeout(Timeo::Fsm(token), Duration::from_millis(ms))
+        }
+        pub fn clear<H>(&mut self, eloop: &mut EventLoop<H>,
+            handle: TimerHandle)
+            -> bool
+            where H: MioHandler
+        {
+            eloop.clear_timeout(&handle)
+        }
+        pub fn arm(&mut self, _now: Time) {
+        }
+    }
+}
+
+#[cfg(feature="precise_time")]
+mod imp {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::collections::BTreeMap;
+    use std::os::unix::io::RawFd;
+
+    use libc;
+    use mio::{Token, Ready, PollOpt};
+    use mio::unix::EventedFd;
+    use mio::deprecated::{EventLoop, Handler as MioHandler};
+
+    use loop_time::micros_until;
+    use {Time, Timeout, TimerError};
+    use super::TIMER_TOKEN;
+
+    /// The deadline and a unique number to tell apart equal deadlines
+    type Key = (Time, u64);
+
+    /// A handle of the deadline
+    ///
+    /// Deadlines set by the loop are served by the timerfd. Other
+    /// implementations of `LoopApi` (e.g. in tests) fall back to the mio's
+    /// timer.
+    #[derive(Debug, Clone)]
+    pub enum TimerHandle {
+        Deadline(Key),
+        Mio(Timeout),
+    }
+
+    /// Deadlines ordered by time, served by a single timerfd
+    pub struct Timers {
+        fd: RawFd,
+        deadlines: BTreeMap<Key, Token>,
+        next_id: u64,
+        /// The deadline the timerfd is currently armed for
+        armed: Option<Time>,
+    }
+
+    impl Timers {
+        pub fn new() -> io::Result<Timers> {
+            let fd = unsafe {
+                libc::timerfd_create(libc::CLOCK_MONOTONIC,
+                    libc::TFD_NONBLOCK | libc::TFD_CLOEXEC)
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Timers {
+                fd: fd,
+                deadlines: BTreeMap::new(),
+                next_id: 0,
+                armed: None,
+            })
+        }
+        pub fn register<H>(&self, eloop: &mut EventLoop<H>)
+            -> io::Result<()>
+            where H: MioHandler
+        {
+            eloop.register(&EventedFd(&self.fd), TIMER_TOKEN,
+                           Ready::readable(), PollOpt::level())
+        }
+        pub fn set<H>(&mut self, _eloop: &mut EventLoop<H>, token: Token,
+            _now: Time, deadline: Time)
+            -> Result<TimerHandle, TimerError>
+            where H: MioHandler
+        {
+            self.next_id += 1;
+            let key = (deadline, self.next_id);
+            self.deadlines.insert(key, token);
+            Ok(TimerHandle::Deadline(key))
+        }
+        pub fn clear<H>(&mut self, eloop: &mut EventLoop<H>,
+            handle: TimerHandle)
+            -> bool
+            where H: MioHandler
+        {
+            match handle {
+                TimerHandle::Deadline(key) => {
+                    self.deadlines.remove(&key).is_some()
+                }
+                TimerHandle::Mio(timeout) => eloop.clear_timeout(&timeout),
+            }
+        }
+        /// Arms the timerfd for the earliest deadline if it isn't yet
+        ///
+        /// If the earliest deadline is removed the timerfd is left armed,
+        /// as a spurious wakeup is cheaper than a system call per removal.
+        pub fn arm(&mut self, now: Time) {
+            let first = match self.deadlines.keys().next() {
+                Some(&(time, _)) => time,
+                None => return,
+            };
+            if self.armed.map(|t| t <= first).unwrap_or(false) {
+                return;
+            }
+            // Zero value disarms the timer, so the past deadlines are set
+            // to expire in a nanosecond
+            let us = micros_until(now, first);
+            let mut value: libc::itimerspec = unsafe { mem::zeroed() };
+            value.it_value.tv_sec = (us / 1000000) as libc::time_t;
+            value.it_value.tv_nsec = ((us % 1000000) * 1000) as libc::c_long;
+            if us == 0 {
+                value.it_value.tv_nsec = 1;
+            }
+            let rc = unsafe {
+                libc::timerfd_settime(self.fd, 0, &value, ptr::null_mut())
+            };
+            if rc < 0 {
+                error!("Can't arm the timer: {}",
+                    io::Error::last_os_error());
+            } else {
+                self.armed = Some(first);
+            }
+        }
+        /// Returns the tokens of the state machines whose deadlines passed
+        ///
+        /// Expired deadlines are removed.
+        pub fn expired(&mut self, now: Time) -> Vec<Token> {
+            let mut buf = [0u8; 8];
+            unsafe {
+                // Only resets readiness, the number of expirations is
+                // irrelevant. May fail with EAGAIN on spurious wakeup.
+                libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len());
+            }
+            self.armed = None;
+            let mut result = Vec::new();
+            loop {
+                let key = match self.deadlines.keys().next() {
+                    Some(&key) if key.0 <= now => key,
+                    _ => break,
+                };
+                result.extend(self.deadlines.remove(&key));
+            }
+            result
+        }
+    }
+
+    impl Drop for Timers {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(feature="precise_time")]
+/// The token of the timerfd in the `precise_time` mode
+///
+/// Has the highest bit set so never clashes with state machine tokens
+/// (see `tokens` module), and is below the ones reserved by mio.
+pub const TIMER_TOKEN: Token = Token(::std::usize::MAX - 3);
+
+#[cfg(all(test, feature="precise_time"))]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use void::{Void, unreachable};
+
+    use scope::DeadlineScope;
+    use test_util::with_scope;
+    use {Machine, Scope, Response, EventSet, Loop, Config, Time};
+
+    struct Tick(u32);
+
+    impl Machine for Tick {
+        type Context = Rc<RefCell<Vec<u32>>>;
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable(seed)
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn spawned(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+        fn timeout(self, scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            scope.borrow_mut().push(self.0);
+            Response::done()
+        }
+        fn wakeup(self, _scope: &mut Scope<Self::Context>)
+            -> Response<Self, Void>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn sub_millisecond_deadlines() {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        // Added in reverse order, all within a millisecond
+        for &id in &[3, 2, 1] {
+            lc.add_machine_with(|scope| {
+                Response::ok(Tick(id))
+                    .deadline(scope.now() + Duration::new(0, id * 300000))
+            }).unwrap();
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        lc.run(log.clone()).unwrap();
+        assert_eq!(*log.borrow(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn fallback_to_mio_timer() {
+        // Scopes which are not created by the loop use the mio's timer
+        let mut log = Rc::new(RefCell::new(Vec::<u32>::new()));
+        with_scope(Time::zero(), &mut log, |scope| {
+            let handle = scope.set_deadline(Time::zero()
+                                            + Duration::from_millis(10))
+                .unwrap();
+            assert!(scope.clear_deadline(handle.clone()));
+            assert!(!scope.clear_deadline(handle));
+        });
+    }
+}
@@ -0,0 +1,94 @@
+//! Encoding of the tokens which are used to register I/O objects
+//!
+//! The token of the state machine (i.e. an index in the slab) is reused
+//! quickly after the state machine exits. I/O objects left registered by
+//! the state machine are deregistered when it exits (see `registry`
+//! module), but the events which are already received in the same
+//! iteration of the loop are still dispatched.
+//!
+//! So the objects are registered with the token tagged by the generation
+//! of the state machine (a part of its unique serial number). Events which
+//! have a different generation than the state machine which currently
+//! owns the token are stale, and are not delivered.
+//!
+//! The token also contains the id of the I/O object (`Source`), so the
+//! state machine which has several objects knows which one is ready.
+//!
+//! On 64-bit platforms the layout is: 32 bits of the index in the slab,
+//! 8 bits of the source and 23 bits of the generation. The highest bit is
+//! kept clear, so tokens never clash with mio's internal ones. On 32-bit
+//! platforms there are few spare bits, so the index is limited to 20 bits
+//! and only 3 bits of the generation are kept. That's enough, because the
+//! objects are deregistered on exit, and only events received in the same
+//! iteration may be stale.
+use mio::Token;
+
+
+#[cfg(target_pointer_width = "64")]
+const INDEX_BITS: usize = 32;
+#[cfg(target_pointer_width = "64")]
+const GENERATION_MASK: u64 = 0x7F_FFFF;
+
+#[cfg(not(target_pointer_width = "64"))]
+const INDEX_BITS: usize = 20;
+#[cfg(not(target_pointer_width = "64"))]
+const GENERATION_MASK: u64 = 0x7;
+
+const SOURCE_BITS: usize = 8;
+
+/// The maximum number of state machines in a loop
+pub const MAX_MACHINES: usize = 1 << INDEX_BITS;
+
+
+/// An identifier of the I/O object registered by a state machine
+///
+/// Objects registered with `Scope::register` have `Source::default()`,
+/// use `Scope::register_source` to choose the id, and
+/// `Machine::ready_source` to find out which object is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Source(pub u8);
+
+/// Returns the generation of the state machine with the serial number
+pub fn generation(serial: u64) -> u64 {
+    serial & GENERATION_MASK
+}
+
+/// Makes the token which is used to register I/O objects
+pub fn encode(token: Token, serial: u64) -> Token {
+    debug_assert!(token.0 >> INDEX_BITS == 0);
+    let tag = generation(serial) << SOURCE_BITS;
+    Token(token.0 | (tag << INDEX_BITS) as usize)
+}
+
+/// Sets the source of the I/O object into the token made by `encode`
+pub fn with_source(token: Token, source: Source) -> Token {
+    let mask = !(((1 << SOURCE_BITS) - 1) << INDEX_BITS);
+    Token(token.0 & mask | (source.0 as usize) << INDEX_BITS)
+}
+
+/// Splits the token of an I/O event into the token of the state machine,
+/// the source and the generation
+pub fn decode(token: Token) -> (Token, Source, u64) {
+    let index_mask = (1usize << INDEX_BITS) - 1;
+    let source = (token.0 >> INDEX_BITS) as u8;
+    let generation = (token.0 >> INDEX_BITS >> SOURCE_BITS) as u64;
+    (Token(token.0 & index_mask), Source(source), generation)
+}
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+    use super::{encode, decode, generation, with_source, Source};
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(encode(Token(5), 0), Token(5));
+        for &serial in &[0, 1, 12345, 0xFFFF_FFFF_FFFF] {
+            for &source in &[Source(0), Source(3), Source(255)] {
+                let token = with_source(encode(Token(17), serial), source);
+                assert_eq!(decode(token), (Token(17), source,
+                                           generation(serial)));
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use mio::Token;
+
+use {Time, Action};
+
+
+/// A single action dispatched to a state machine
+///
+/// Records are produced by the loop when tracing is enabled with
+/// `Config::trace` or `Config::trace_buffer`
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// The loop time when the action was dispatched
+    pub time: Time,
+    /// The token of the state machine
+    pub token: Token,
+    /// The action
+    pub action: Action,
+    /// The kind of the response returned (`ok`, `spawn`, `done`, ...)
+    ///
+    /// It's `panic` if the state machine panicked
+    pub response: &'static str,
+    /// The deadline of the state machine before the action
+    pub old_deadline: Option<Time>,
+    /// The deadline of the state machine after the action
+    pub deadline: Option<Time>,
+    /// How long the action took
+    pub duration: Duration,
+}
+
+/// A ring buffer of the last trace records
+///
+/// This is a cheaply clonable handle, so it may be put into a panic hook
+/// or sent to another thread to dump the records on demand.
+#[derive(Debug, Clone)]
+pub struct TraceBuffer {
+    capacity: usize,
+    records: Arc<Mutex<VecDeque<TraceRecord>>>,
+}
+
+/// Writes the records to the log and the buffer
+#[derive(Debug)]
+pub struct Tracer {
+    buffer: Option<TraceBuffer>,
+}
+
+/// Formats an optional deadline as the time or `none`
+struct Deadline(Option<Time>);
+
+impl fmt::Display for Deadline {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(time) => write!(fmt, "{}", time),
+            None => write!(fmt, "none"),
+        }
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "{} {:?} {:?} -> {}", self.time, self.token,
+            self.action, self.response));
+        if self.deadline != self.old_deadline {
+            try!(write!(fmt, ", deadline {} -> {}",
+                Deadline(self.old_deadline), Deadline(self.deadline)));
+        }
+        write!(fmt, " in {:?}", self.duration)
+    }
+}
+
+impl TraceBuffer {
+    /// Creates a buffer keeping at most `capacity` records
+    pub fn new(capacity: usize) -> TraceBuffer {
+        TraceBuffer {
+            capacity: capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, VecDeque<TraceRecord>> {
+        // The buffer may be dumped in a panic hook, so we ignore poisoning
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Adds a record, removing the oldest one if the buffer is full
+    pub fn push(&self, record: TraceRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.lock();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+    /// Returns a copy of the records, the oldest first
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.lock().iter().cloned().collect()
+    }
+    /// Formats the records one per line, the oldest first
+    pub fn dump(&self) -> String {
+        let mut result = String::new();
+        for record in self.lock().iter() {
+            result.push_str(&format!("{}\n", record));
+        }
+        result
+    }
+    /// Removes all records
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+impl Tracer {
+    pub fn new(buffer: Option<TraceBuffer>) -> Tracer {
+        Tracer { buffer: buffer }
+    }
+    pub fn buffer(&self) -> Option<&TraceBuffer> {
+        self.buffer.as_ref()
+    }
+    pub fn record(&self, record: TraceRecord) {
+        trace!(target: "rotor::trace", "{}", record);
+        if let Some(ref buffer) = self.buffer {
+            buffer.push(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use mio::Token;
+
+    use {Time, Action};
+    use super::{TraceBuffer, TraceRecord};
+
+    fn record(token: usize) -> TraceRecord {
+        TraceRecord {
+            time: Time::zero(),
+            token: Token(token),
+            action: Action::Timeout,
+            response: "ok",
+            old_deadline: None,
+            deadline: Some(Time::zero() + Duration::from_millis(10)),
+            duration: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let buf = TraceBuffer::new(2);
+        buf.push(record(1));
+        buf.push(record(2));
+        buf.clone().push(record(3));
+        let tokens = buf.records().iter().map(|r| r.token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![Token(2), Token(3)]);
+        #[cfg(not(feature="precise_time"))]
+        assert_eq!(buf.dump().lines().next().unwrap(),
+            "0.000s Token(2) Timeout -> ok, \
+             deadline none -> 0.010s in 1ms");
+        #[cfg(feature="precise_time")]
+        assert_eq!(buf.dump().lines().next().unwrap(),
+            "0.000000s Token(2) Timeout -> ok, \
+             deadline none -> 0.010000s in 1ms");
+    }
+}
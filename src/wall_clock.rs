@@ -0,0 +1,246 @@
+use std::cmp::min;
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime};
+
+use void::{Void, unreachable};
+
+use {Machine, Scope, Response, EventSet, Time};
+
+
+/// A deadline at the wall-clock (system) time
+///
+/// The loop works with monotonic `Time` only, so the wall-clock time is
+/// converted to the monotonic deadline. But the system clock may be
+/// adjusted in the meantime (by NTP, by an administrator, or after the
+/// machine wakes from a suspend), so the deadline is never put further
+/// than the recheck interval (a minute by default). On every timeout the
+/// state machine checks whether the wall-clock time has come, and if not,
+/// sets a new deadline:
+///
+/// ```ignore
+/// fn timeout(self, scope: &mut Scope<Context>) -> Response<Self, Void> {
+///     if self.at.is_due() {
+///         self.run_job(scope)
+///     } else {
+///         let deadline = self.at.deadline(scope.now());
+///         Response::ok(self).deadline(deadline)
+///     }
+/// }
+/// ```
+///
+/// Note: the loop itself knows nothing about the wall-clock time, it only
+/// sees the monotonic deadline returned by the state machine. So it's up
+/// to the state machine to call `is_due` and `deadline` on every timeout,
+/// as shown above, otherwise a clock jump is not noticed. `WallTimer` does
+/// this for the state machine that does nothing else.
+///
+/// Use `Cron` to find out the time of the next run for periodic jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallDeadline {
+    at: SystemTime,
+    recheck: Duration,
+}
+
+impl WallDeadline {
+    /// Creates a deadline at the specified system time
+    pub fn new(at: SystemTime) -> WallDeadline {
+        WallDeadline {
+            at: at,
+            recheck: Duration::from_secs(60),
+        }
+    }
+    /// Sets the maximum interval between checks of the system clock
+    ///
+    /// This is how late the state machine may be woken up when the clock
+    /// jumps forward.
+    pub fn recheck_interval(&mut self, interval: Duration) {
+        self.recheck = interval;
+    }
+    /// Returns the wall-clock time of the deadline
+    pub fn at(&self) -> SystemTime {
+        self.at
+    }
+    /// Returns `true` if the system time has reached the deadline
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(SystemTime::now())
+    }
+    /// Returns `true` if the deadline is not later than `now`
+    pub fn is_due_at(&self, now: SystemTime) -> bool {
+        self.at <= now
+    }
+    /// Returns the loop deadline for the next check
+    ///
+    /// It's either the estimated deadline or `now` plus the recheck
+    /// interval, whichever is earlier. It's `now` if the deadline is due.
+    pub fn deadline(&self, now: Time) -> Time {
+        self.deadline_at(now, SystemTime::now())
+    }
+    fn deadline_at(&self, now: Time, system_now: SystemTime) -> Time {
+        match self.at.duration_since(system_now) {
+            Ok(left) => now.saturating_add(min(left, self.recheck)),
+            Err(_) => now,
+        }
+    }
+}
+
+/// A state machine which calls the function at the wall-clock time
+///
+/// The function returns the wall-clock time of the next call, or `None` to
+/// stop the state machine. The system clock is rechecked at least every
+/// recheck interval of the `WallDeadline` (see `recheck_interval`), so the
+/// function is called in time when the clock jumps forward, and isn't
+/// called too early when the clock jumps backward.
+///
+/// ```ignore
+/// let cron = Cron::parse("0 3 * * *").unwrap();
+/// let first = cron.next_after(SystemTime::now()).unwrap();
+/// WallTimer::new(WallDeadline::new(first), move |scope: &mut Scope<C>| {
+///     scope.cleanup();
+///     cron.next_after(SystemTime::now())
+/// }, scope)
+/// ```
+pub struct WallTimer<C, F> {
+    at: WallDeadline,
+    callback: F,
+    clock: fn() -> SystemTime,
+    phantom: PhantomData<*const C>,
+}
+
+impl<C, F> WallTimer<C, F>
+    where F: FnMut(&mut Scope<C>) -> Option<SystemTime>
+{
+    pub fn new<N>(at: WallDeadline, callback: F, scope: &mut Scope<C>)
+        -> Response<WallTimer<C, F>, N>
+    {
+        WallTimer {
+            at: at,
+            callback: callback,
+            clock: SystemTime::now,
+            phantom: PhantomData,
+        }.schedule(scope.now())
+    }
+    fn schedule<N>(self, now: Time) -> Response<Self, N> {
+        let deadline = self.at.deadline_at(now, (self.clock)());
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+impl<C, F> Machine for WallTimer<C, F>
+    where F: FnMut(&mut Scope<C>) -> Option<SystemTime>
+{
+    type Context = C;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        self.schedule(scope.now())
+    }
+    fn spawned(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.schedule(scope.now())
+    }
+    fn timeout(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        if self.at.is_due_at((self.clock)()) {
+            match (self.callback)(scope) {
+                Some(next) => self.at.at = next,
+                None => return Response::done(),
+            }
+        }
+        self.schedule(scope.now())
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.schedule(scope.now())
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("wall timer at {:?}", self.at.at))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::error::Error;
+    use std::marker::PhantomData;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use mio::Token;
+    use void::Void;
+
+    use response::decompose;
+    use test_util::with_scope;
+    use {Time, Machine, Scope, Response};
+    use super::{WallDeadline, WallTimer};
+
+    #[test]
+    fn deadline() {
+        let now = Time::zero();
+        let sys = SystemTime::now();
+        let mut at = WallDeadline::new(sys + Duration::from_secs(3600));
+        assert!(!at.is_due_at(sys));
+        assert_eq!(at.deadline_at(now, sys), now + Duration::from_secs(60));
+        at.recheck_interval(Duration::from_secs(7200));
+        assert_eq!(at.deadline_at(now, sys),
+                   now + Duration::from_secs(3600));
+        // the clock jumped forward
+        let later = sys + Duration::from_secs(4000);
+        assert!(at.is_due_at(later));
+        assert_eq!(at.deadline_at(now, later), now);
+    }
+
+    type Calls = Cell<u32>;
+    type Timer = WallTimer<Calls, fn(&mut Scope<Calls>) -> Option<SystemTime>>;
+
+    thread_local! {
+        static CLOCK: Cell<u64> = Cell::new(1_000_000);
+    }
+
+    /// The simulated system clock, in seconds since the epoch
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(CLOCK.with(|c| c.get()))
+    }
+
+    fn call(scope: &mut Scope<Calls>) -> Option<SystemTime> {
+        scope.set(scope.get() + 1);
+        None
+    }
+
+    fn dispatch<F>(time: Time, calls: &mut Calls, f: F)
+        -> (Result<Timer, Option<Box<Error>>>, Vec<Void>, Option<Time>)
+        where F: FnOnce(&mut Scope<Calls>) -> Response<Timer, Void>
+    {
+        with_scope(time, calls, |scope| decompose(Token(0), f(scope)))
+    }
+
+    #[test]
+    fn clock_jump() {
+        let start = Time::zero();
+        let minute = Duration::from_secs(60);
+        let mut calls = Cell::new(0);
+        let timer = Timer {
+            at: WallDeadline::new(clock() + Duration::from_secs(3600)),
+            callback: call,
+            clock: clock,
+            phantom: PhantomData,
+        };
+        let (timer, _, deadline) = dispatch(start, &mut calls,
+            |s| timer.schedule(s.now()));
+        assert_eq!(deadline, Some(start + minute));
+
+        // the time has not come yet, so the timer is rearmed
+        CLOCK.with(|c| c.set(c.get() + 60));
+        let (timer, _, deadline) = dispatch(start + minute, &mut calls,
+            |s| timer.ok().unwrap().timeout(s));
+        assert_eq!(calls.get(), 0);
+        assert_eq!(deadline, Some(start + minute * 2));
+
+        // the clock jumped forward past the deadline
+        CLOCK.with(|c| c.set(c.get() + 4000));
+        let (timer, _, _) = dispatch(start + minute * 2, &mut calls,
+            |s| timer.ok().unwrap().timeout(s));
+        assert_eq!(calls.get(), 1);
+        assert!(timer.is_err(), "timer is not done");
+    }
+}